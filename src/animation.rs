@@ -0,0 +1,154 @@
+use crate::spritesheet::{self, Clip};
+use bevy::prelude::*;
+
+// Plays clips of the sprites sheet by switching the atlas index of the entity, every frame
+// keeps its own collision mask that way
+pub struct AnimationPlugin;
+
+impl Plugin for AnimationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<AnimationFinished>()
+            .add_system(animation_system);
+    }
+}
+
+#[derive(Component)]
+pub struct Animation {
+    // Clips are looked up as "<sprite>_<clip>" in the sprites sheet
+    sprite: String,
+    clip: String,
+    frame: usize,
+    // Seconds the current frame has been shown
+    elapsed: f32,
+    finished: bool,
+}
+
+// Sent once when a clip that doesn't loop shows its last frame for the whole frame time
+pub struct AnimationFinished {
+    pub entity: Entity,
+    pub clip: String,
+}
+
+impl Animation {
+    pub fn new(sprite: &str, clip: &str) -> Animation {
+        Animation {
+            sprite: sprite.to_string(),
+            clip: clip.to_string(),
+            frame: 0,
+            elapsed: 0.0,
+            finished: false,
+        }
+    }
+
+    // Switches to clip from its first frame, keeps going if it already plays
+    pub fn play(&mut self, clip: &str) {
+        if self.clip != clip {
+            *self = Animation::new(&self.sprite, clip);
+        }
+    }
+
+    // Moves delta seconds forward, true when the clip just finished
+    fn advance(&mut self, clip: &Clip, delta: f32) -> bool {
+        if self.finished {
+            return false;
+        }
+        self.elapsed += delta;
+        while self.elapsed >= clip.frame_time {
+            self.elapsed -= clip.frame_time;
+            if self.frame + 1 < clip.frames.len() {
+                self.frame += 1;
+            } else if clip.looping {
+                self.frame = 0;
+            } else {
+                self.finished = true;
+                return true;
+            }
+        }
+        false
+    }
+}
+
+fn animation_system(
+    time: Res<Time>,
+    sprites: Res<spritesheet::Sprites>,
+    sheets: Res<Assets<spritesheet::SpriteSheet>>,
+    mut finished_events: EventWriter<AnimationFinished>,
+    mut query: Query<(Entity, &mut Animation, &mut TextureAtlasSprite)>,
+) {
+    let sheet = match sheets.get(&sprites.0) {
+        Some(sheet) => sheet,
+        None => return,
+    };
+    for (entity, mut animation, mut sprite) in &mut query {
+        let name = format!("{}_{}", animation.sprite, animation.clip);
+        let just_finished = match sheet.clips.get(&name) {
+            Some(clip) => {
+                let finished = animation.advance(clip, time.delta_seconds());
+                let frame = clip.frames[animation.frame];
+                if sprite.index != frame {
+                    sprite.index = frame;
+                }
+                finished
+            }
+            // Nothing to show, a missing clip ends right away
+            None => !std::mem::replace(&mut animation.finished, true),
+        };
+        if just_finished {
+            finished_events.send(AnimationFinished {
+                entity,
+                clip: animation.clip.clone(),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(frames: usize, looping: bool) -> Clip {
+        Clip {
+            frames: (0..frames).collect(),
+            frame_time: 0.1,
+            looping,
+        }
+    }
+
+    #[test]
+    fn looping_clip_starts_over() {
+        let clip = clip(3, true);
+        let mut animation = Animation::new("ship", "idle");
+        assert!(!animation.advance(&clip, 0.05));
+        assert_eq!(0, animation.frame);
+        assert!(!animation.advance(&clip, 0.1));
+        assert_eq!(1, animation.frame);
+        // Long frames skip as many frames as fit in
+        assert!(!animation.advance(&clip, 0.2));
+        assert_eq!(0, animation.frame);
+    }
+
+    #[test]
+    fn one_shot_clip_finishes_once_on_last_frame() {
+        let clip = clip(2, false);
+        let mut animation = Animation::new("enemy", "death");
+        assert!(!animation.advance(&clip, 0.15));
+        assert_eq!(1, animation.frame);
+        assert!(animation.advance(&clip, 0.1));
+        assert_eq!(1, animation.frame);
+        assert!(!animation.advance(&clip, 1.0));
+        assert_eq!(1, animation.frame);
+    }
+
+    #[test]
+    fn play_restarts_only_other_clips() {
+        let clip = clip(3, true);
+        let mut animation = Animation::new("ship", "idle");
+        animation.advance(&clip, 0.15);
+        animation.play("idle");
+        assert_eq!(1, animation.frame);
+        animation.play("thrust");
+        assert_eq!("thrust", animation.clip);
+        assert_eq!(0, animation.frame);
+        assert_eq!(0.0, animation.elapsed);
+    }
+}
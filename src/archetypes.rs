@@ -0,0 +1,291 @@
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+use rand::{distributions::WeightedIndex, rngs::StdRng, Rng};
+
+use crate::parse::{number, ParseError};
+
+// Enemy archetypes are text files with one kind of enemy per line:
+//   <kind> <sprite> <weight> <health> <min speed>-<max speed> <score> <size> <movement> <weapon>
+// Kind is the letter levels use for the enemy, sprite its region in the sprites sheet and
+// weight how often it is picked for random spawns (zero for level only enemies). Speed is on
+// top of the map scroll speed, size is the side of the square of tiles the enemy needs to
+// fly through. Movement is path (around the tiles), straight (down, whatever is in the
+// way), sine, homing, dive, orbit or charge (see behaviors). Weapon is the name of the
+// weapon or none. Lines starting with // are comments.
+pub struct ArchetypesPlugin;
+
+impl Plugin for ArchetypesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<EnemyArchetypes>()
+            .init_asset_loader::<ArchetypesLoader>()
+            .add_startup_system(load_archetypes);
+    }
+}
+
+// Archetypes the game spawns enemies from
+pub const ARCHETYPES: &str = "enemies/archetypes.enemies";
+
+pub struct Archetypes(pub Handle<EnemyArchetypes>);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Movement {
+    Path,
+    Straight,
+    Sine,
+    Homing,
+    Dive,
+    Orbit,
+    Charge,
+}
+
+impl Movement {
+    pub fn parse(name: &str) -> Option<Movement> {
+        match name {
+            "path" => Some(Movement::Path),
+            "straight" => Some(Movement::Straight),
+            "sine" => Some(Movement::Sine),
+            "homing" => Some(Movement::Homing),
+            "dive" => Some(Movement::Dive),
+            "orbit" => Some(Movement::Orbit),
+            "charge" => Some(Movement::Charge),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnemyArchetype {
+    pub kind: char,
+    pub sprite: String,
+    pub weight: u32,
+    pub health: i32,
+    pub min_speed: f32,
+    pub max_speed: f32,
+    pub score: usize,
+    pub size: i32,
+    pub movement: Movement,
+    pub weapon: Option<String>,
+}
+
+impl EnemyArchetype {
+    pub fn random_speed(&self, rng: &mut StdRng) -> f32 {
+        rng.gen_range(self.min_speed..=self.max_speed)
+    }
+
+    // Copy with health and speed multiplied by difficulty, at least one health is left
+    pub fn scaled(&self, difficulty: f32) -> EnemyArchetype {
+        EnemyArchetype {
+            health: ((self.health as f32 * difficulty).round() as i32).max(1),
+            min_speed: self.min_speed * difficulty,
+            max_speed: self.max_speed * difficulty,
+            ..self.clone()
+        }
+    }
+}
+
+#[derive(TypeUuid)]
+#[uuid = "9a4e7c21-3f5b-4d8a-b6e2-1c7f0d9a8e53"]
+pub struct EnemyArchetypes {
+    pub archetypes: Vec<EnemyArchetype>,
+}
+
+impl EnemyArchetypes {
+    pub fn get(&self, kind: char) -> Option<&EnemyArchetype> {
+        self.archetypes
+            .iter()
+            .find(|archetype| archetype.kind == kind)
+    }
+
+    // Random archetype by weight
+    pub fn random(&self, rng: &mut StdRng) -> &EnemyArchetype {
+        let weights = WeightedIndex::new(self.archetypes.iter().map(|a| a.weight)).unwrap();
+        &self.archetypes[rng.sample(weights)]
+    }
+}
+
+fn load_archetypes(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(Archetypes(asset_server.load(ARCHETYPES)));
+}
+
+pub fn parse_archetypes(text: &str) -> Result<EnemyArchetypes, ParseError> {
+    let mut archetypes: Vec<EnemyArchetype> = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let error = |message: String| ParseError {
+            line: line_index + 1,
+            message,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() != 9 {
+            return Err(error(format!("expected 9 fields, not {}", fields.len())));
+        }
+        let kind = match fields[0].chars().collect::<Vec<char>>()[..] {
+            [kind @ 'A'..='Z'] => kind,
+            _ => return Err(error(format!("kind '{}' is not a letter A-Z", fields[0]))),
+        };
+        if archetypes.iter().any(|archetype| archetype.kind == kind) {
+            return Err(error(format!("kind {} is already defined", kind)));
+        }
+        let (min_speed, max_speed) = match fields[4].split_once('-') {
+            Some((min, max)) => (number(min).map_err(error)?, number(max).map_err(error)?),
+            None => return Err(error(format!("speed '{}' is not <min>-<max>", fields[4]))),
+        };
+        if min_speed > max_speed {
+            return Err(error("minimal speed is over the maximal one".to_string()));
+        }
+        let health: i32 = number(fields[3]).map_err(error)?;
+        let size: i32 = number(fields[6]).map_err(error)?;
+        if health < 1 || size < 1 {
+            return Err(error("health and size have to be at least one".to_string()));
+        }
+        let movement = match Movement::parse(fields[7]) {
+            Some(movement) => movement,
+            None => return Err(error(format!("unknown movement '{}'", fields[7]))),
+        };
+        archetypes.push(EnemyArchetype {
+            kind,
+            sprite: fields[1].to_string(),
+            weight: number(fields[2]).map_err(error)?,
+            health,
+            min_speed,
+            max_speed,
+            score: number(fields[5]).map_err(error)?,
+            size,
+            movement,
+            weapon: match fields[8] {
+                "none" => None,
+                weapon => Some(weapon.to_string()),
+            },
+        });
+    }
+    if !archetypes.iter().any(|archetype| archetype.weight > 0) {
+        return Err(ParseError {
+            line: 1,
+            message: "no archetype to pick for random spawns".to_string(),
+        });
+    }
+    Ok(EnemyArchetypes { archetypes })
+}
+
+#[derive(Default)]
+pub struct ArchetypesLoader;
+
+impl AssetLoader for ArchetypesLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let archetypes = parse_archetypes(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(archetypes));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["enemies"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn parse_archetype_fields() {
+        let archetypes = parse_archetypes(
+            "// kind sprite weight health speed score size movement weapon\n\
+             A enemy_A 10 1 0-100 1 3 path none\n\
+             \n\
+             B enemy_B 0 4 20.5-40 5 1 straight cannon\n\
+             C enemy_A 1 2 0-10 3 3 homing none\n",
+        )
+        .unwrap();
+        assert_eq!(
+            EnemyArchetype {
+                kind: 'B',
+                sprite: "enemy_B".to_string(),
+                weight: 0,
+                health: 4,
+                min_speed: 20.5,
+                max_speed: 40.0,
+                score: 5,
+                size: 1,
+                movement: Movement::Straight,
+                weapon: Some("cannon".to_string()),
+            },
+            archetypes.archetypes[1]
+        );
+        assert_eq!(Movement::Path, archetypes.get('A').unwrap().movement);
+        assert_eq!(None, archetypes.get('A').unwrap().weapon);
+        assert_eq!(Movement::Homing, archetypes.get('C').unwrap().movement);
+        assert!(archetypes.get('D').is_none());
+    }
+
+    #[test]
+    fn parse_errors_have_line() {
+        let valid = "A enemy_A 1 1 0-1 1 3 path none\n";
+        let line_of = |line: &str| {
+            parse_archetypes(&format!("{}{}", valid, line))
+                .err()
+                .unwrap()
+                .line
+        };
+        assert_eq!(2, line_of("a enemy_a 1 1 0-1 1 3 path none"));
+        assert_eq!(2, line_of("A enemy_A 1 1 0-1 1 3 path none"));
+        assert_eq!(2, line_of("B enemy_B 1 1 5 1 3 path none"));
+        assert_eq!(2, line_of("B enemy_B 1 1 5-1 1 3 path none"));
+        assert_eq!(2, line_of("B enemy_B 1 0 0-1 1 3 path none"));
+        assert_eq!(2, line_of("B enemy_B 1 1 0-1 1 3 zigzag none"));
+        assert_eq!(2, line_of("B enemy_B 1 1 0-1 1 3 path"));
+        assert_eq!(
+            "line 1: 'x' is not a number",
+            parse_archetypes("A enemy_A x 1 0-1 1 3 path none")
+                .err()
+                .unwrap()
+                .to_string()
+        );
+        assert!(parse_archetypes("A enemy_A 0 1 0-1 1 3 path none").is_err());
+    }
+
+    #[test]
+    fn random_follows_weights() {
+        let archetypes = parse_archetypes(
+            "A a 1 1 0-1 1 3 path none\nB b 3 1 0-1 1 3 path none\nC c 0 1 0-1 1 3 path none",
+        )
+        .unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let kinds: Vec<char> = (0..1000)
+            .map(|_| archetypes.random(&mut rng).kind)
+            .collect();
+        assert!(!kinds.contains(&'C'));
+        let a = kinds.iter().filter(|kind| **kind == 'A').count();
+        assert!(a > 150 && a < 350, "{} A enemies", a);
+    }
+
+    #[test]
+    fn scaled_keeps_some_health() {
+        let archetypes = parse_archetypes("A a 1 3 10-20 1 3 path none").unwrap();
+        let archetype = archetypes.get('A').unwrap();
+        let harder = archetype.scaled(1.5);
+        assert_eq!(5, harder.health);
+        assert_eq!((15.0, 30.0), (harder.min_speed, harder.max_speed));
+        assert_eq!(archetype.score, harder.score);
+        assert_eq!(1, archetype.scaled(0.1).health);
+    }
+
+    #[test]
+    fn game_archetypes_parse() {
+        let archetypes =
+            parse_archetypes(include_str!("../assets/enemies/archetypes.enemies")).unwrap();
+        assert!(archetypes.get('A').is_some());
+    }
+}
@@ -0,0 +1,89 @@
+use crate::config;
+use crate::map;
+use bevy::prelude::*;
+
+// Layers under the map scrolling slower than the tiles so they look farther away
+pub struct BackgroundPlugin;
+
+impl Plugin for BackgroundPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_startup_system(setup_background)
+            .add_system(parallax_system);
+    }
+}
+
+#[derive(Component)]
+struct Layer {
+    speed_factor: f32,
+    scrolled: f32,
+}
+
+fn setup_background(mut commands: Commands, asset_server: Res<AssetServer>) {
+    for (index, (texture, speed_factor)) in config::BACKGROUND_LAYERS.iter().enumerate() {
+        let z = config::BACKGROUND_Z + index as f32;
+        commands
+            .spawn_bundle(SpatialBundle::from_transform(Transform::from_xyz(
+                0.0, 0.0, z,
+            )))
+            .insert(Layer {
+                speed_factor: *speed_factor,
+                scrolled: 0.0,
+            })
+            .with_children(|layer| {
+                // The second copy right above the first fills the screen while it scrolls
+                for copy in 0..2 {
+                    layer.spawn_bundle(SpriteBundle {
+                        texture: asset_server.load(*texture),
+                        sprite: Sprite {
+                            custom_size: Some(config::MAP_BOUNDS),
+                            ..default()
+                        },
+                        transform: Transform::from_xyz(
+                            0.0,
+                            copy as f32 * config::MAP_BOUNDS.y,
+                            0.0,
+                        ),
+                        ..default()
+                    });
+                }
+            });
+    }
+}
+
+// Where the layer is after scrolling, always within one height under zero so one of the
+// two copies covers the screen
+fn wrapped_offset(scrolled: f32, height: f32) -> f32 {
+    -scrolled.rem_euclid(height)
+}
+
+fn parallax_system(map: Res<map::Map>, mut query: Query<(&mut Layer, &mut Transform)>) {
+    for (mut layer, mut transform) in &mut query {
+        // Follows the map speed so the layers speed up and slow down with the ship
+        layer.scrolled += map.scroll_speed * layer.speed_factor * config::TIME_STEP;
+        transform.translation.y = wrapped_offset(layer.scrolled, config::MAP_BOUNDS.y);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offset_wraps_around_height() {
+        assert_eq!(0.0, wrapped_offset(0.0, 100.0));
+        assert_eq!(-30.0, wrapped_offset(30.0, 100.0));
+        assert_eq!(-30.0, wrapped_offset(230.0, 100.0));
+        // Scrolling back when the ship slows down below zero speed
+        assert_eq!(-70.0, wrapped_offset(-30.0, 100.0));
+    }
+
+    #[test]
+    fn layers_get_slower_with_distance() {
+        let factors: Vec<f32> = config::BACKGROUND_LAYERS
+            .iter()
+            .map(|layer| layer.1)
+            .collect();
+        assert!(factors.windows(2).all(|pair| pair[0] < pair[1]));
+        assert!(factors.iter().all(|factor| *factor > 0.0 && *factor < 1.0));
+    }
+}
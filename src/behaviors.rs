@@ -0,0 +1,291 @@
+use bevy::prelude::*;
+use std::f32::consts::TAU;
+
+use crate::archetypes::Movement;
+use crate::config;
+
+// How an enemy flies, with the state its movement keeps. Enemies head down the screen
+// towards the player, speeds are on top of the map scroll speed like for straight enemies.
+#[derive(Component, Clone, Debug, PartialEq)]
+pub enum Behavior {
+    // Along the waypoints of Enemy::path around the tiles, with the map while there is none
+    PathFollow,
+    // Down over the tiles
+    Straight,
+    // Down swaying from side to side around the x it started at
+    Sine { center: f32, time: f32 },
+    // After the player turning at most config::HOMING_TURN_RATE, back down once past it
+    Homing { heading: Vec2 },
+    // Down until the player is in range, then straight at where it was
+    DiveBomb { dive: Option<Vec2> },
+    // Around a point going down
+    Orbit { center: Vec3, angle: f32 },
+    // Down to config::HOVER_Y, holds there for a while and charges at where the player is
+    HoverCharge { hovered: f32, charge: Option<Vec2> },
+}
+
+// What behaviors steer by
+pub struct Steering {
+    pub player: Vec3,
+    // Next waypoint of the path
+    pub waypoint: Option<Vec3>,
+    // Of the enemy
+    pub speed: f32,
+    pub scroll_speed: f32,
+}
+
+impl Behavior {
+    // Behavior of the movement for an enemy spawned at translation
+    pub fn new(movement: Movement, translation: Vec3) -> Behavior {
+        match movement {
+            Movement::Path => Behavior::PathFollow,
+            Movement::Straight => Behavior::Straight,
+            Movement::Sine => Behavior::Sine {
+                center: translation.x,
+                time: 0.0,
+            },
+            Movement::Homing => Behavior::Homing {
+                heading: Vec2::NEG_Y,
+            },
+            Movement::Dive => Behavior::DiveBomb { dive: None },
+            Movement::Orbit => Behavior::Orbit {
+                // Starts at the top of the circle
+                center: translation - Vec3::Y * config::ORBIT_RADIUS,
+                angle: 0.0,
+            },
+            Movement::Charge => Behavior::HoverCharge {
+                hovered: 0.0,
+                charge: None,
+            },
+        }
+    }
+
+    // Position after dt seconds from position
+    pub fn step(&mut self, position: Vec3, steering: &Steering, dt: f32) -> Vec3 {
+        let distance = (steering.speed + steering.scroll_speed) * dt;
+        let down = position - Vec3::Y * distance;
+        match self {
+            Behavior::PathFollow => match steering.waypoint {
+                Some(waypoint) => position + (waypoint - position).clamp_length_max(distance),
+                // Keeps its place over the tiles until a way opens
+                None => position - Vec3::Y * steering.scroll_speed * dt,
+            },
+            Behavior::Straight => down,
+            Behavior::Sine { center, time } => {
+                *time += dt;
+                let sway = (TAU * config::SINE_FREQUENCY * *time).sin();
+                Vec3::new(*center + config::SINE_AMPLITUDE * sway, down.y, down.z)
+            }
+            Behavior::Homing { heading } => {
+                let wanted = if position.y > steering.player.y {
+                    (steering.player - position)
+                        .truncate()
+                        .try_normalize()
+                        .unwrap_or(*heading)
+                } else {
+                    Vec2::NEG_Y
+                };
+                let max_turn = config::HOMING_TURN_RATE * dt;
+                let turn = heading.angle_between(wanted).clamp(-max_turn, max_turn);
+                *heading = Mat2::from_angle(turn) * *heading;
+                position + heading.extend(0.0) * distance
+            }
+            Behavior::DiveBomb { dive } => {
+                if dive.is_none() && position.distance(steering.player) <= config::DIVE_RANGE {
+                    *dive = Some(aim(position, steering.player));
+                }
+                match dive {
+                    Some(dive) => position + dive.extend(0.0) * config::DIVE_SPEED * dt,
+                    None => down,
+                }
+            }
+            Behavior::Orbit { center, angle } => {
+                center.y -= distance;
+                *angle += config::ORBIT_ANGULAR_SPEED * dt;
+                *center + Vec3::new(angle.sin(), angle.cos(), 0.0) * config::ORBIT_RADIUS
+            }
+            Behavior::HoverCharge { hovered, charge } => {
+                if let Some(charge) = charge {
+                    return position + charge.extend(0.0) * config::CHARGE_SPEED * dt;
+                }
+                if position.y > config::HOVER_Y {
+                    return Vec3::new(position.x, down.y.max(config::HOVER_Y), position.z);
+                }
+                *hovered += dt;
+                if *hovered >= config::HOVER_TIME {
+                    *charge = Some(aim(position, steering.player));
+                }
+                position
+            }
+        }
+    }
+}
+
+// Direction from position to target, down when they are at the same place
+fn aim(position: Vec3, target: Vec3) -> Vec2 {
+    (target - position)
+        .truncate()
+        .try_normalize()
+        .unwrap_or(Vec2::NEG_Y)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DT: f32 = 0.1;
+
+    fn steering(player: Vec3) -> Steering {
+        Steering {
+            player,
+            waypoint: None,
+            speed: 50.0,
+            scroll_speed: 50.0,
+        }
+    }
+
+    fn assert_near(expected: Vec3, actual: Vec3) {
+        assert!(
+            expected.distance(actual) < 0.01,
+            "{} != {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn path_follow_heads_to_waypoint_and_keeps_with_map_without_one() {
+        let mut behavior = Behavior::new(Movement::Path, Vec3::ZERO);
+        let mut along_path = steering(Vec3::ZERO);
+        along_path.waypoint = Some(Vec3::new(100.0, 0.0, 0.0));
+        assert_near(
+            Vec3::new(10.0, 0.0, 0.0),
+            behavior.step(Vec3::ZERO, &along_path, DT),
+        );
+        // Does not overshoot
+        along_path.waypoint = Some(Vec3::new(3.0, 0.0, 0.0));
+        assert_near(
+            Vec3::new(3.0, 0.0, 0.0),
+            behavior.step(Vec3::ZERO, &along_path, DT),
+        );
+        assert_near(
+            Vec3::new(0.0, -5.0, 0.0),
+            behavior.step(Vec3::ZERO, &steering(Vec3::ZERO), DT),
+        );
+    }
+
+    #[test]
+    fn sine_sways_around_start() {
+        let start = Vec3::new(100.0, 0.0, 0.0);
+        let mut behavior = Behavior::new(Movement::Sine, start);
+        let mut position = start;
+        let mut xs: Vec<f32> = Vec::new();
+        // One full sway
+        for _ in 0..(1.0 / config::SINE_FREQUENCY / DT).round() as usize {
+            position = behavior.step(position, &steering(Vec3::ZERO), DT);
+            xs.push(position.x);
+        }
+        assert_near(
+            Vec3::new(100.0, -100.0 / config::SINE_FREQUENCY, 0.0),
+            position,
+        );
+        let max = xs.iter().cloned().fold(f32::MIN, f32::max);
+        let min = xs.iter().cloned().fold(f32::MAX, f32::min);
+        assert!((max - 100.0 - config::SINE_AMPLITUDE).abs() < 0.01);
+        assert!((100.0 - min - config::SINE_AMPLITUDE).abs() < 0.01);
+    }
+
+    #[test]
+    fn homing_turns_at_most_turn_rate() {
+        let mut behavior = Behavior::new(Movement::Homing, Vec3::ZERO);
+        // Player straight to the right and below
+        let player = Vec3::new(1000.0, -1.0, 0.0);
+        behavior.step(Vec3::ZERO, &steering(player), DT);
+        let turned = match behavior {
+            Behavior::Homing { heading } => Vec2::NEG_Y.angle_between(heading),
+            _ => unreachable!(),
+        };
+        assert!((turned - config::HOMING_TURN_RATE * DT).abs() < 0.001);
+        // Eventually flies at the player
+        let mut position = Vec3::ZERO;
+        for _ in 0..50 {
+            position = behavior.step(position, &steering(Vec3::new(200.0, -1000.0, 0.0)), DT);
+        }
+        let heading = match behavior {
+            Behavior::Homing { heading } => heading,
+            _ => unreachable!(),
+        };
+        let to_player = (Vec3::new(200.0, -1000.0, 0.0) - position)
+            .truncate()
+            .normalize();
+        assert!(heading.angle_between(to_player).abs() < 0.01);
+    }
+
+    #[test]
+    fn homing_goes_down_past_player() {
+        let mut behavior = Behavior::Homing { heading: Vec2::Y };
+        let mut position = Vec3::ZERO;
+        for _ in 0..100 {
+            position = behavior.step(position, &steering(Vec3::new(0.0, 50.0, 0.0)), DT);
+        }
+        match behavior {
+            Behavior::Homing { heading } => assert!(heading.distance(Vec2::NEG_Y) < 0.001),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn dive_bomb_dives_once_player_in_range() {
+        let mut behavior = Behavior::new(Movement::Dive, Vec3::ZERO);
+        let far = Vec3::new(0.0, -config::DIVE_RANGE - 100.0, 0.0);
+        assert_near(
+            Vec3::new(0.0, -10.0, 0.0),
+            behavior.step(Vec3::ZERO, &steering(far), DT),
+        );
+        let near = Vec3::new(30.0, -40.0, 0.0);
+        let dived = behavior.step(Vec3::ZERO, &steering(near), DT);
+        assert_near(Vec3::new(0.6, -0.8, 0.0) * config::DIVE_SPEED * DT, dived);
+        // Keeps the direction when the player moves away
+        assert_near(dived * 2.0, behavior.step(dived, &steering(far), DT));
+    }
+
+    #[test]
+    fn orbit_circles_around_point_going_down() {
+        let start = Vec3::new(0.0, 100.0, 0.0);
+        let mut behavior = Behavior::new(Movement::Orbit, start);
+        let angle = config::ORBIT_ANGULAR_SPEED * DT;
+        let center = start - Vec3::new(0.0, config::ORBIT_RADIUS + 10.0, 0.0);
+        let mut position = behavior.step(start, &steering(Vec3::ZERO), DT);
+        assert_near(
+            center + Vec3::new(angle.sin(), angle.cos(), 0.0) * config::ORBIT_RADIUS,
+            position,
+        );
+        for _ in 0..100 {
+            position = behavior.step(position, &steering(Vec3::ZERO), DT);
+            if let Behavior::Orbit { center, .. } = behavior {
+                assert!((position.distance(center) - config::ORBIT_RADIUS).abs() < 0.01);
+            }
+        }
+    }
+
+    #[test]
+    fn hover_charge_hovers_then_charges() {
+        let start = Vec3::new(0.0, config::HOVER_Y + 5.0, 0.0);
+        let mut behavior = Behavior::new(Movement::Charge, start);
+        let player = Vec3::new(0.0, -300.0, 0.0);
+        let mut position = behavior.step(start, &steering(player), DT);
+        // Stops at the hover line
+        assert_near(Vec3::new(0.0, config::HOVER_Y, 0.0), position);
+        let mut hover_steps: usize = 0;
+        while let Behavior::HoverCharge { charge: None, .. } = behavior {
+            position = behavior.step(position, &steering(player), DT);
+            assert_near(Vec3::new(0.0, config::HOVER_Y, 0.0), position);
+            hover_steps += 1;
+        }
+        assert!(hover_steps.abs_diff((config::HOVER_TIME / DT).round() as usize) <= 1);
+        assert_near(
+            Vec3::new(0.0, config::HOVER_Y - config::CHARGE_SPEED * DT, 0.0),
+            behavior.step(position, &steering(player), DT),
+        );
+    }
+}
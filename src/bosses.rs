@@ -0,0 +1,547 @@
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+
+use crate::animation::{Animation, AnimationFinished};
+use crate::collision;
+use crate::config;
+use crate::enemies;
+use crate::health::{self, DeathEvent, Health, HitFlash};
+use crate::map;
+use crate::parse::{number, ParseError};
+use crate::player;
+use crate::spritesheet::SheetSprites;
+use crate::ui;
+use crate::weapons::Weapon;
+
+// Bosses are text files describing one boss, one part per line:
+//   sprite <region>                 body of the boss in the sprites sheet
+//   size <tiles>                    side of the square of tiles the boss takes
+//   score <points>                  for destroying it
+//   zone <sprite> <x> <y> <health>  hit zone at x, y tiles from the middle of the body
+//   phase <health percent> <movement> <speed> <weapon>
+//                                   from when the health of all zones drops to the percent,
+//                                   the first phase starts at 100. Movement is hold, sweep
+//                                   (from wall to wall) or chase (after the player).
+// The boss is destroyed with its last zone. The map stops scrolling while it fights.
+// Lines starting with // are comments.
+pub struct BossesPlugin;
+
+impl Plugin for BossesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<BossDescriptor>()
+            .init_asset_loader::<BossLoader>()
+            .add_system(boss_movement_system)
+            .add_system(boss_zones_system.after(boss_movement_system))
+            .add_system(boss_health_system.after(health::DamageApplication))
+            .add_system(despawn_dead_bosses_system);
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BossMovement {
+    Hold,
+    Sweep,
+    Chase,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BossZone {
+    pub sprite: String,
+    // In tiles from the middle of the body
+    pub offset: Vec2,
+    pub health: i32,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct BossPhase {
+    // Starts once the health drops to this percent of the full one
+    pub threshold: f32,
+    pub movement: BossMovement,
+    pub speed: f32,
+    pub weapon: Option<String>,
+}
+
+#[derive(Debug, PartialEq, TypeUuid)]
+#[uuid = "e4b7a2d1-8c35-4f6e-9a0b-3d5c7e1f2a84"]
+pub struct BossDescriptor {
+    pub sprite: String,
+    pub size: i32,
+    pub score: usize,
+    pub zones: Vec<BossZone>,
+    // From the first one
+    pub phases: Vec<BossPhase>,
+}
+
+#[derive(Component)]
+pub struct Boss {
+    // Sum of the health of the zones
+    pub health: i32,
+    pub max_health: i32,
+    phases: Vec<BossPhase>,
+    phase: usize,
+    size: i32,
+    score: usize,
+    // Flies in until it reaches config::BOSS_Y
+    entered: bool,
+    // Sweeping to the right when positive
+    direction: f32,
+}
+
+// Hit zone of a boss, it moves with the body
+#[derive(Component)]
+pub struct HitZone {
+    boss: Entity,
+    offset: Vec2,
+}
+
+// Index of the phase for health left out of max health
+pub fn phase_at(phases: &[BossPhase], health: i32, max_health: i32) -> usize {
+    let percent = 100.0 * health as f32 / max_health as f32;
+    phases
+        .iter()
+        .rposition(|phase| percent <= phase.threshold)
+        .unwrap_or(0)
+}
+
+// New x and direction of a boss at x moving distance this frame, target is the x of the
+// player and fits tells whether the boss fits at an x
+pub fn boss_step(
+    movement: BossMovement,
+    x: f32,
+    direction: f32,
+    target: f32,
+    distance: f32,
+    fits: impl Fn(f32) -> bool,
+) -> (f32, f32) {
+    let new_x = match movement {
+        BossMovement::Hold => return (x, direction),
+        BossMovement::Sweep => x + direction * distance,
+        BossMovement::Chase => x + (target - x).clamp(-distance, distance),
+    };
+    if fits(new_x) {
+        (new_x, direction)
+    } else {
+        // Turns around at walls
+        (x, -direction)
+    }
+}
+
+// Spawns the boss above the middle of the screen with its health scaled by difficulty
+pub fn spawn_boss(
+    commands: &mut Commands,
+    sprites: &SheetSprites,
+    descriptor: &BossDescriptor,
+    difficulty: f32,
+) {
+    let half_size = descriptor.size as f32 * config::TILE_SIDE / 2.0;
+    let translation = Vec3::new(0.0, config::MAP_BOUNDS.y / 2.0 + half_size, 0.0);
+    let boss = sprites
+        .spawn(
+            commands,
+            &descriptor.sprite,
+            Transform::from_translation(translation),
+        )
+        .insert(Animation::new(&descriptor.sprite, "idle"))
+        // Keeps it in the fight like other enemies
+        .insert(enemies::Advancing {
+            movement_speed: config::BOSS_ENTRY_SPEED,
+        })
+        // The ship crashes into the body, shots only hit the zones
+        .insert(collision::Collider {
+            layer: collision::LAYER_ENEMY,
+            mask: collision::LAYER_PLAYER,
+        })
+        .id();
+    let mut max_health = 0;
+    for zone in &descriptor.zones {
+        let health = ((zone.health as f32 * difficulty).round() as i32).max(1);
+        max_health += health;
+        let offset = zone.offset * config::TILE_SIDE;
+        // Over the body
+        let transform = Transform::from_translation(translation + offset.extend(0.1));
+        sprites
+            .spawn(commands, &zone.sprite, transform)
+            .insert(HitZone { boss, offset })
+            .insert(Health::new(health))
+            .insert(HitFlash::default())
+            .insert(collision::Collider {
+                layer: collision::LAYER_ENEMY,
+                mask: collision::LAYER_SHOT,
+            });
+    }
+    commands.entity(boss).insert(Boss {
+        health: max_health,
+        max_health,
+        phases: descriptor.phases.clone(),
+        phase: 0,
+        size: descriptor.size,
+        score: descriptor.score,
+        entered: false,
+        direction: 1.0,
+    });
+    if let Some(weapon) = &descriptor.phases[0].weapon {
+        commands.entity(boss).insert(Weapon::new(weapon));
+    }
+}
+
+fn boss_movement_system(
+    map: Res<map::Map>,
+    player_query: Query<&Transform, (With<player::Player>, Without<Boss>)>,
+    mut boss_query: Query<(&mut Boss, &mut Transform, &enemies::Advancing)>,
+) {
+    let target = player_query
+        .get_single()
+        .map(|player| player.translation.x)
+        .unwrap_or(0.0);
+    for (mut boss, mut transform, advancing) in &mut boss_query {
+        if !boss.entered {
+            transform.translation.y -= advancing.movement_speed * config::TIME_STEP;
+            if transform.translation.y <= config::BOSS_Y {
+                transform.translation.y = config::BOSS_Y;
+                boss.entered = true;
+            }
+            continue;
+        }
+        let phase = &boss.phases[boss.phase];
+        let fits = |x: f32| {
+            let pos = map::Pos::from_world_vec3(&Vec3::new(x, config::BOSS_Y, 0.0));
+            map::fits(&map.grid, pos, boss.size)
+        };
+        let (x, direction) = boss_step(
+            phase.movement,
+            transform.translation.x,
+            boss.direction,
+            target,
+            phase.speed * config::TIME_STEP,
+            fits,
+        );
+        transform.translation.x = x;
+        boss.direction = direction;
+    }
+}
+
+fn boss_zones_system(
+    boss_query: Query<&Transform, (With<Boss>, Without<HitZone>)>,
+    mut zone_query: Query<(&HitZone, &mut Transform)>,
+) {
+    for (zone, mut transform) in &mut zone_query {
+        if let Ok(boss) = boss_query.get(zone.boss) {
+            transform.translation = boss.translation + zone.offset.extend(0.1);
+        }
+    }
+}
+
+// Removes destroyed zones, switches phases as the health drops and destroys the boss with
+// its last zone
+fn boss_health_system(
+    mut commands: Commands,
+    mut scoreboard: ResMut<ui::Scoreboard>,
+    mut death_events: EventReader<DeathEvent>,
+    zone_query: Query<(&HitZone, &Health)>,
+    mut boss_query: Query<(Entity, &mut Boss, &mut Animation), With<enemies::Advancing>>,
+) {
+    for death in death_events.iter() {
+        if zone_query.get(death.entity).is_ok() {
+            commands.entity(death.entity).despawn();
+        }
+    }
+    for (entity, mut boss, mut animation) in &mut boss_query {
+        boss.health = zone_query
+            .iter()
+            .filter(|(zone, _)| zone.boss == entity)
+            .map(|(_, health)| health.current)
+            .sum();
+        if boss.health <= 0 {
+            scoreboard.score += boss.score;
+            commands
+                .entity(entity)
+                .remove::<enemies::Advancing>()
+                .remove::<Weapon>();
+            animation.play("death");
+            continue;
+        }
+        let phase = phase_at(&boss.phases, boss.health, boss.max_health);
+        if phase != boss.phase {
+            boss.phase = phase;
+            match &boss.phases[phase].weapon {
+                Some(weapon) => commands.entity(entity).insert(Weapon::new(weapon)),
+                None => commands.entity(entity).remove::<Weapon>(),
+            };
+        }
+    }
+}
+
+fn despawn_dead_bosses_system(
+    mut commands: Commands,
+    mut finished_events: EventReader<AnimationFinished>,
+    query: Query<Entity, With<Boss>>,
+) {
+    for finished in finished_events.iter() {
+        if finished.clip == "death" && query.get(finished.entity).is_ok() {
+            commands.entity(finished.entity).despawn();
+        }
+    }
+}
+
+pub fn parse_boss(text: &str) -> Result<BossDescriptor, ParseError> {
+    let mut sprite: Option<String> = None;
+    let mut size: Option<i32> = None;
+    let mut score: usize = 0;
+    let mut zones: Vec<BossZone> = Vec::new();
+    let mut phases: Vec<BossPhase> = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let error = |message: String| ParseError {
+            line: line_index + 1,
+            message,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let expected = match fields[0] {
+            "sprite" | "size" | "score" => 2,
+            "zone" => 5,
+            "phase" => 5,
+            _ => return Err(error(format!("unknown part '{}'", fields[0]))),
+        };
+        if fields.len() != expected {
+            return Err(error(format!(
+                "{} takes {} arguments, not {}",
+                fields[0],
+                expected - 1,
+                fields.len() - 1
+            )));
+        }
+        match fields[0] {
+            "sprite" => sprite = Some(fields[1].to_string()),
+            "size" => {
+                let tiles: i32 = number(fields[1]).map_err(error)?;
+                if tiles < 1 {
+                    return Err(error("size has to be at least one".to_string()));
+                }
+                size = Some(tiles);
+            }
+            "score" => score = number(fields[1]).map_err(error)?,
+            "zone" => {
+                let health: i32 = number(fields[4]).map_err(error)?;
+                if health < 1 {
+                    return Err(error("zone health has to be at least one".to_string()));
+                }
+                zones.push(BossZone {
+                    sprite: fields[1].to_string(),
+                    offset: Vec2::new(
+                        number(fields[2]).map_err(error)?,
+                        number(fields[3]).map_err(error)?,
+                    ),
+                    health,
+                });
+            }
+            _ => {
+                let threshold: f32 = number(fields[1]).map_err(error)?;
+                let first = phases.is_empty();
+                if first && threshold != 100.0 {
+                    return Err(error("first phase has to start at 100".to_string()));
+                }
+                if !first && threshold >= phases.last().unwrap().threshold {
+                    return Err(error(
+                        "phases have to go from the full health down".to_string(),
+                    ));
+                }
+                let movement = match fields[2] {
+                    "hold" => BossMovement::Hold,
+                    "sweep" => BossMovement::Sweep,
+                    "chase" => BossMovement::Chase,
+                    _ => return Err(error(format!("unknown movement '{}'", fields[2]))),
+                };
+                phases.push(BossPhase {
+                    threshold,
+                    movement,
+                    speed: number(fields[3]).map_err(error)?,
+                    weapon: match fields[4] {
+                        "none" => None,
+                        weapon => Some(weapon.to_string()),
+                    },
+                });
+            }
+        }
+    }
+    let error = |message: &str| ParseError {
+        line: 1,
+        message: message.to_string(),
+    };
+    let sprite = sprite.ok_or_else(|| error("boss has no sprite"))?;
+    let size = size.ok_or_else(|| error("boss has no size"))?;
+    if zones.is_empty() || phases.is_empty() {
+        return Err(error("boss needs a zone and a phase"));
+    }
+    Ok(BossDescriptor {
+        sprite,
+        size,
+        score,
+        zones,
+        phases,
+    })
+}
+
+#[derive(Default)]
+pub struct BossLoader;
+
+impl AssetLoader for BossLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let boss = parse_boss(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(boss));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["boss"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::weapons;
+
+    fn phase(threshold: f32, movement: BossMovement) -> BossPhase {
+        BossPhase {
+            threshold,
+            movement,
+            speed: 50.0,
+            weapon: None,
+        }
+    }
+
+    #[test]
+    fn parse_boss_parts() {
+        let boss = parse_boss(
+            "// the big one\n\
+             sprite boss_body\n\
+             size 6\n\
+             score 100\n\
+             \n\
+             zone boss_core 0 0 30\n\
+             zone boss_turret -2 -1.5 10\n\
+             phase 100 hold 0 cannon\n\
+             phase 40 chase 80 none\n",
+        )
+        .unwrap();
+        assert_eq!(
+            BossDescriptor {
+                sprite: "boss_body".to_string(),
+                size: 6,
+                score: 100,
+                zones: vec![
+                    BossZone {
+                        sprite: "boss_core".to_string(),
+                        offset: Vec2::ZERO,
+                        health: 30,
+                    },
+                    BossZone {
+                        sprite: "boss_turret".to_string(),
+                        offset: Vec2::new(-2.0, -1.5),
+                        health: 10,
+                    },
+                ],
+                phases: vec![
+                    BossPhase {
+                        weapon: Some("cannon".to_string()),
+                        speed: 0.0,
+                        ..phase(100.0, BossMovement::Hold)
+                    },
+                    BossPhase {
+                        speed: 80.0,
+                        ..phase(40.0, BossMovement::Chase)
+                    },
+                ],
+            },
+            boss
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_line() {
+        let valid = "sprite body\nsize 4\nzone core 0 0 5\nphase 100 hold 0 none\n";
+        let line_of = |line: &str| {
+            parse_boss(&format!("{}{}", valid, line))
+                .err()
+                .unwrap()
+                .line
+        };
+        assert_eq!(5, line_of("wing 1"));
+        assert_eq!(5, line_of("size 0"));
+        assert_eq!(5, line_of("zone core 0 0"));
+        assert_eq!(5, line_of("zone core 0 0 0"));
+        assert_eq!(5, line_of("phase 100 hold 0 none"));
+        assert_eq!(5, line_of("phase 50 dance 0 none"));
+        assert_eq!(
+            "line 1: first phase has to start at 100",
+            parse_boss("phase 90 hold 0 none")
+                .err()
+                .unwrap()
+                .to_string()
+        );
+        assert!(parse_boss("sprite body\nsize 4\nzone core 0 0 5").is_err());
+    }
+
+    #[test]
+    fn phases_follow_health() {
+        let phases = vec![
+            phase(100.0, BossMovement::Hold),
+            phase(60.0, BossMovement::Sweep),
+            phase(25.0, BossMovement::Chase),
+        ];
+        assert_eq!(0, phase_at(&phases, 100, 100));
+        assert_eq!(0, phase_at(&phases, 61, 100));
+        assert_eq!(1, phase_at(&phases, 30, 50));
+        assert_eq!(2, phase_at(&phases, 1, 4));
+    }
+
+    #[test]
+    fn boss_step_turns_at_walls() {
+        let inside = |x: f32| x.abs() <= 10.0;
+        assert_eq!(
+            (0.0, 1.0),
+            boss_step(BossMovement::Hold, 0.0, 1.0, 5.0, 2.0, inside)
+        );
+        assert_eq!(
+            (2.0, 1.0),
+            boss_step(BossMovement::Sweep, 0.0, 1.0, 0.0, 2.0, inside)
+        );
+        assert_eq!(
+            (9.0, -1.0),
+            boss_step(BossMovement::Sweep, 9.0, 1.0, 0.0, 2.0, inside)
+        );
+        // Chasing doesn't overshoot the player
+        assert_eq!(
+            (-1.0, 1.0),
+            boss_step(BossMovement::Chase, 0.0, 1.0, -1.0, 2.0, inside)
+        );
+        assert_eq!(
+            (2.0, 1.0),
+            boss_step(BossMovement::Chase, 0.0, 1.0, 8.0, 2.0, inside)
+        );
+    }
+
+    #[test]
+    fn game_bosses_parse() {
+        let boss = parse_boss(include_str!("../assets/bosses/dreadnought.boss")).unwrap();
+        let weapons =
+            weapons::parse_weapons(include_str!("../assets/enemies/weapons.weapons")).unwrap();
+        for phase in boss.phases {
+            if let Some(weapon) = phase.weapon {
+                assert!(weapons.get(&weapon).is_some(), "{} is not defined", weapon);
+            }
+        }
+    }
+}
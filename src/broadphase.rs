@@ -0,0 +1,315 @@
+use bevy::{prelude::*, utils::HashMap};
+use std::marker::PhantomData;
+
+use crate::collision;
+use crate::config;
+
+// The grid is built at the start of the frame and things move a bit before the collision
+// systems query it, so the queried area is grown by this margin
+const QUERY_MARGIN: f32 = config::TILE_SIDE / 2.0;
+
+// Adds a SpatialGrid<T> resource holding all entities with the T component,
+// rebuilt every frame
+pub struct SpatialGridPlugin<T> {
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for SpatialGridPlugin<T> {
+    fn default() -> Self {
+        SpatialGridPlugin {
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T: Component> Plugin for SpatialGridPlugin<T> {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<SpatialGrid<T>>()
+            .add_system_to_stage(CoreStage::PreUpdate, update_spatial_grid_system::<T>);
+    }
+}
+
+// Uniform grid of map tile sized cells, each cell lists the entities whose bounds touch it
+pub struct SpatialGrid<T> {
+    cells: HashMap<IVec2, Vec<Entity>>,
+    marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Default for SpatialGrid<T> {
+    fn default() -> Self {
+        SpatialGrid {
+            cells: HashMap::default(),
+            marker: PhantomData,
+        }
+    }
+}
+
+impl<T> SpatialGrid<T> {
+    pub fn clear(&mut self) {
+        // Keep the cells around, the same ones are very likely to be used next frame
+        for entities in self.cells.values_mut() {
+            entities.clear();
+        }
+    }
+
+    pub fn insert(&mut self, entity: Entity, transform: &Transform, img_size: Vec2) {
+        let (min, max) = collision::bounds(transform, img_size);
+        let (min_cell, max_cell) = (to_cell(min), to_cell(max));
+        for y in min_cell.y..=max_cell.y {
+            for x in min_cell.x..=max_cell.x {
+                self.cells.entry(IVec2::new(x, y)).or_default().push(entity);
+            }
+        }
+    }
+
+    // Entities that could be in the axis aligned box (min, max), each returned once
+    pub fn query(&self, min: Vec2, max: Vec2) -> Vec<Entity> {
+        let min_cell = to_cell(min - Vec2::splat(QUERY_MARGIN));
+        let max_cell = to_cell(max + Vec2::splat(QUERY_MARGIN));
+        let mut found: Vec<Entity> = Vec::new();
+        for y in min_cell.y..=max_cell.y {
+            for x in min_cell.x..=max_cell.x {
+                if let Some(entities) = self.cells.get(&IVec2::new(x, y)) {
+                    found.extend(entities);
+                }
+            }
+        }
+        found.sort_unstable();
+        found.dedup();
+        found
+    }
+}
+
+fn to_cell(world: Vec2) -> IVec2 {
+    (world / config::TILE_SIDE).floor().as_ivec2()
+}
+
+fn update_spatial_grid_system<T: Component>(
+    masks: Res<collision::CollisionMasks>,
+    mut grid: ResMut<SpatialGrid<T>>,
+    query: Query<
+        (
+            Entity,
+            &Transform,
+            &Handle<TextureAtlas>,
+            &TextureAtlasSprite,
+        ),
+        With<T>,
+    >,
+) {
+    grid.clear();
+    for (entity, transform, atlas, sprite) in &query {
+        // Without a mask it can't collide anyway
+        if let Some(mask) = masks.get(atlas, sprite) {
+            grid.insert(entity, transform, mask.size());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bevy::utils::HashSet;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use std::time::Instant;
+
+    struct Marker;
+
+    fn generate_mask(width: u32, height: u32) -> collision::CollisionMask {
+        collision::CollisionMask::from_image(&Image::new_fill(
+            bevy::render::render_resource::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+            bevy::render::render_resource::TextureDimension::D2,
+            &[255, 255, 255, 255],
+            bevy::render::render_resource::TextureFormat::Rgba8Uint,
+        ))
+    }
+
+    fn query_image(
+        grid: &SpatialGrid<Marker>,
+        transform: &Transform,
+        img_size: Vec2,
+    ) -> Vec<Entity> {
+        let (min, max) = collision::bounds(transform, img_size);
+        grid.query(min, max)
+    }
+
+    #[test]
+    fn query_finds_only_nearby() {
+        let mut grid = SpatialGrid::<Marker>::default();
+        let size = Vec2::new(32.0, 32.0);
+        grid.insert(
+            Entity::from_raw(0),
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            size,
+        );
+        grid.insert(
+            Entity::from_raw(1),
+            &Transform::from_xyz(64.0, 0.0, 0.0),
+            size,
+        );
+        grid.insert(
+            Entity::from_raw(2),
+            &Transform::from_xyz(-320.0, 0.0, 0.0),
+            size,
+        );
+        grid.insert(
+            Entity::from_raw(3),
+            &Transform::from_xyz(0.0, -500.0, 0.0),
+            size,
+        );
+
+        assert_eq!(
+            vec![Entity::from_raw(0)],
+            query_image(
+                &grid,
+                &Transform::from_xyz(-10.0, 5.0, 0.0),
+                Vec2::new(4.0, 4.0)
+            )
+        );
+        assert_eq!(
+            vec![Entity::from_raw(0), Entity::from_raw(1)],
+            query_image(
+                &grid,
+                &Transform::from_xyz(32.0, 0.0, 0.0),
+                Vec2::new(4.0, 4.0)
+            )
+        );
+        assert_eq!(
+            vec![Entity::from_raw(3)],
+            query_image(
+                &grid,
+                &Transform::from_xyz(10.0, -490.0, 0.0),
+                Vec2::new(4.0, 4.0)
+            )
+        );
+        assert!(query_image(&grid, &Transform::from_xyz(-160.0, 200.0, 0.0), size).is_empty());
+    }
+
+    #[test]
+    fn query_returns_entities_once() {
+        let mut grid = SpatialGrid::<Marker>::default();
+        // Covers many cells
+        grid.insert(
+            Entity::from_raw(7),
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            Vec2::new(200.0, 200.0),
+        );
+        assert_eq!(
+            vec![Entity::from_raw(7)],
+            query_image(
+                &grid,
+                &Transform::from_xyz(0.0, 0.0, 0.0),
+                Vec2::new(150.0, 150.0)
+            )
+        );
+    }
+
+    #[test]
+    fn clear_removes_entities() {
+        let mut grid = SpatialGrid::<Marker>::default();
+        let size = Vec2::new(32.0, 32.0);
+        grid.insert(
+            Entity::from_raw(0),
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            size,
+        );
+        grid.clear();
+        assert!(query_image(&grid, &Transform::from_xyz(0.0, 0.0, 0.0), size).is_empty());
+    }
+
+    #[test]
+    fn query_follows_rotation_and_scale() {
+        let mut grid = SpatialGrid::<Marker>::default();
+        let mut trans = Transform::from_xyz(0.0, 0.0, 0.0);
+        trans.scale = Vec3::new(10.0, 1.0, 1.0);
+        trans.rotate_z(f32::to_radians(90.0));
+        grid.insert(Entity::from_raw(0), &trans, Vec2::new(32.0, 32.0));
+
+        assert_eq!(
+            vec![Entity::from_raw(0)],
+            query_image(
+                &grid,
+                &Transform::from_xyz(0.0, 150.0, 0.0),
+                Vec2::new(4.0, 4.0)
+            )
+        );
+        assert!(query_image(
+            &grid,
+            &Transform::from_xyz(150.0, 0.0, 0.0),
+            Vec2::new(4.0, 4.0)
+        )
+        .is_empty());
+    }
+
+    // Run with: cargo test broadphase_benchmark -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn broadphase_benchmark() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let tile_mask = generate_mask(32, 32);
+        let shot_mask = generate_mask(8, 8);
+
+        // Sparse walls over a map much bigger than the screen
+        let tiles: Vec<(Entity, Transform)> = (0..4000)
+            .map(|i| {
+                let cell = IVec2::new(rng.gen_range(-100..100), rng.gen_range(-100..100));
+                let pos = cell.as_vec2() * config::TILE_SIDE;
+                (Entity::from_raw(i), Transform::from_xyz(pos.x, pos.y, 0.0))
+            })
+            .collect();
+        let shots: Vec<Transform> = (0..4000)
+            .map(|_| {
+                Transform::from_xyz(
+                    rng.gen_range(-3200.0..3200.0),
+                    rng.gen_range(-3200.0..3200.0),
+                    0.0,
+                )
+            })
+            .collect();
+
+        let start = Instant::now();
+        // Shot index and tile of every hit
+        let mut brute_force_hits: HashSet<(usize, Entity)> = HashSet::new();
+        for (index, shot) in shots.iter().enumerate() {
+            for (entity, tile) in &tiles {
+                if collision::collide(shot, &shot_mask, tile, &tile_mask) {
+                    brute_force_hits.insert((index, *entity));
+                }
+            }
+        }
+        let brute_force_time = start.elapsed();
+
+        let start = Instant::now();
+        let mut grid = SpatialGrid::<Marker>::default();
+        for (entity, tile) in &tiles {
+            grid.insert(*entity, tile, tile_mask.size());
+        }
+        let mut grid_hits: HashSet<(usize, Entity)> = HashSet::new();
+        for (index, shot) in shots.iter().enumerate() {
+            for entity in query_image(&grid, shot, shot_mask.size()) {
+                let (_, tile) = &tiles[entity.id() as usize];
+                if collision::collide(shot, &shot_mask, tile, &tile_mask) {
+                    grid_hits.insert((index, entity));
+                }
+            }
+        }
+        let grid_time = start.elapsed();
+
+        // Timings are only shown with --nocapture, they depend too much on the machine to
+        // assert on
+        println!(
+            "{} tiles x {} shots, {} hits: brute force {:?}, grid {:?} ({:.1}x faster)",
+            tiles.len(),
+            shots.len(),
+            grid_hits.len(),
+            brute_force_time,
+            grid_time,
+            brute_force_time.as_secs_f64() / grid_time.as_secs_f64()
+        );
+        assert_eq!(brute_force_hits, grid_hits);
+    }
+}
@@ -1,84 +1,718 @@
-use bevy::prelude::*;
+use bevy::{asset::HandleId, prelude::*, utils::HashMap};
 
+use crate::broadphase;
 //use crate::debug;
 
-//TODO(amatej): use Separating Axis Theorem: for not axis align collision detection
+// Rotations closer than this to a quarter turn are snapped to it so that the common
+// 90/180/270 degree cases index pixels exactly
+const ROTATION_SNAP_EPSILON: f32 = 0.0001;
+
+const MASK_WORD_BITS: usize = u64::BITS as usize;
+
+// Collision layers, colliders collide when the layer of one is in the mask of the other
+pub const LAYER_PLAYER: u32 = 1 << 0;
+pub const LAYER_SHOT: u32 = 1 << 1;
+pub const LAYER_ENEMY: u32 = 1 << 2;
+pub const LAYER_ENEMY_SHOT: u32 = 1 << 3;
+
+pub struct CollisionPlugin;
+
+impl Plugin for CollisionPlugin {
+    fn build(&self, app: &mut App) {
+        app.init_resource::<CollisionMasks>()
+            .add_event::<CollisionEvent>()
+            .add_plugin(broadphase::SpatialGridPlugin::<Collider>::default())
+            .add_system(update_collision_masks_system)
+            .add_system(detect_collisions_system.label(CollisionDetection));
+    }
+}
+
+// Systems reacting to CollisionEvents should run after this label
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct CollisionDetection;
+
+#[derive(Component)]
+pub struct Collider {
+    pub layer: u32,
+    pub mask: u32,
+}
+
+impl Collider {
+    fn interacts_with(&self, other: &Collider) -> bool {
+        self.mask & other.layer != 0 || other.mask & self.layer != 0
+    }
+}
+
+// Sent once per frame for every pair of colliding entities
+pub struct CollisionEvent {
+    pub a: Entity,
+    pub b: Entity,
+    // As seen from a, the normal points away from b
+    pub contact: Contact,
+}
+
+impl CollisionEvent {
+    // Returns the pair with the entity for which is_first holds first
+    pub fn ordered(&self, is_first: impl Fn(Entity) -> bool) -> (Entity, Entity) {
+        if is_first(self.a) {
+            (self.a, self.b)
+        } else {
+            (self.b, self.a)
+        }
+    }
+}
+
+// Fast moving colliders (projectiles) can opt into continuous collision. They are then
+// checked along the whole way they went since the previous detection so they can't
+// tunnel through thin objects.
+#[derive(Component)]
+pub struct ContinuousCollision {
+    previous: Transform,
+}
+
+impl ContinuousCollision {
+    pub fn new(spawn_transform: &Transform) -> ContinuousCollision {
+        ContinuousCollision {
+            previous: *spawn_transform,
+        }
+    }
+}
+
+// Each pair is checked just once, by the side with the lower rank: continuous colliders
+// first (so the sweep is used), then the ones searching for others (non zero mask)
+fn pair_rank(entity: Entity, collider: &Collider, continuous: bool) -> (bool, bool, Entity) {
+    (!continuous, collider.mask == 0, entity)
+}
+
+// All colliders for the detection and the continuous ones to update afterwards
+type ColliderQueries<'w, 's> = ParamSet<
+    'w,
+    's,
+    (
+        Query<
+            'w,
+            's,
+            (
+                Entity,
+                &'static Transform,
+                &'static Handle<TextureAtlas>,
+                &'static TextureAtlasSprite,
+                &'static Collider,
+                Option<&'static ContinuousCollision>,
+            ),
+        >,
+        Query<'w, 's, (&'static Transform, &'static mut ContinuousCollision)>,
+    ),
+>;
+
+fn detect_collisions_system(
+    masks: Res<CollisionMasks>,
+    grid: Res<broadphase::SpatialGrid<Collider>>,
+    mut queries: ColliderQueries,
+    mut collision_events: EventWriter<CollisionEvent>,
+) {
+    let query = queries.p0();
+    for (a, transform_a, atlas_a, sprite_a, collider_a, continuous_a) in &query {
+        let rank_a = pair_rank(a, collider_a, continuous_a.is_some());
+        // Colliders that don't look for anything are found by the others
+        if collider_a.mask == 0 && continuous_a.is_none() {
+            continue;
+        }
+        let mask_a = match masks.get(atlas_a, sprite_a) {
+            Some(mask) => mask,
+            None => continue,
+        };
+        let from_a = match continuous_a {
+            Some(continuous) => &continuous.previous,
+            None => transform_a,
+        };
+        let (min, max) = swept_bounds(from_a, transform_a, mask_a.size());
+        for b in grid.query(min, max) {
+            if let Ok((_, transform_b, atlas_b, sprite_b, collider_b, continuous_b)) = query.get(b)
+            {
+                if rank_a >= pair_rank(b, collider_b, continuous_b.is_some()) {
+                    continue;
+                }
+                if !collider_a.interacts_with(collider_b) {
+                    continue;
+                }
+                // When both are continuous only the sweep of a is used, b is taken
+                // where it is now
+                if let Some(mask_b) = masks.get(atlas_b, sprite_b) {
+                    if let Some(contact) =
+                        contact_swept(from_a, transform_a, mask_a, transform_b, mask_b)
+                    {
+                        collision_events.send(CollisionEvent { a, b, contact });
+                    }
+                }
+            }
+        }
+    }
+
+    for (transform, mut continuous) in &mut queries.p1() {
+        continuous.previous = *transform;
+    }
+}
+
+// Bit packed alpha of an image, one bit per pixel (set when the pixel is not transparent).
+// Rows start at the top of the image and each row is padded to whole words.
+pub struct CollisionMask {
+    width: usize,
+    height: usize,
+    words_per_row: usize,
+    bits: Vec<u64>,
+}
+
+impl CollisionMask {
+    #[cfg(test)]
+    pub fn from_image(img: &Image) -> CollisionMask {
+        CollisionMask::from_region(img, UVec2::ZERO, img.size().as_uvec2())
+    }
+
+    // Mask of the part of the image starting at min, like a rect of a texture atlas
+    pub fn from_region(img: &Image, min: UVec2, size: UVec2) -> CollisionMask {
+        let img_width = img.size().x as usize;
+        let (min_x, min_y) = (min.x as usize, min.y as usize);
+        let width = size.x as usize;
+        let height = size.y as usize;
+        let words_per_row = width.div_ceil(MASK_WORD_BITS);
+        let mut bits = vec![0; words_per_row * height];
+        for y in 0..height {
+            for x in 0..width {
+                if img.data[(min_x + x + (min_y + y) * img_width) * 4 + 3] >= 1 {
+                    bits[y * words_per_row + x / MASK_WORD_BITS] |= 1 << (x % MASK_WORD_BITS);
+                }
+            }
+        }
+        CollisionMask {
+            width,
+            height,
+            words_per_row,
+            bits,
+        }
+    }
+
+    pub fn size(&self) -> Vec2 {
+        Vec2::new(self.width as f32, self.height as f32)
+    }
+
+    fn is_opaque(&self, x: usize, y: usize) -> bool {
+        self.bits[y * self.words_per_row + x / MASK_WORD_BITS] >> (x % MASK_WORD_BITS) & 1 == 1
+    }
+
+    // Returns len (at most one word) bits of the row starting at column start,
+    // the lowest bit is the start column
+    fn row_bits(&self, y: usize, start: usize, len: usize) -> u64 {
+        let row = &self.bits[y * self.words_per_row..(y + 1) * self.words_per_row];
+        let word = start / MASK_WORD_BITS;
+        let shift = start % MASK_WORD_BITS;
+        let mut bits = row[word] >> shift;
+        if shift != 0 && word + 1 < self.words_per_row {
+            bits |= row[word + 1] << (MASK_WORD_BITS - shift);
+        }
+        if len < MASK_WORD_BITS {
+            bits &= (1 << len) - 1;
+        }
+        bits
+    }
+
+    // Same as row_bits but walks the row in the given direction, for direction -1
+    // the lowest bit is the start column and higher bits go to the left
+    fn row_run(&self, y: usize, start: usize, direction: i32, len: usize) -> u64 {
+        if direction > 0 {
+            return self.row_bits(y, start, len);
+        }
+        let bits = self.row_bits(y, start + 1 - len, len);
+        bits.reverse_bits() >> (MASK_WORD_BITS - len)
+    }
+}
+
+// Masks of every rect of the loaded texture atlases, built once when the atlas and its
+// image are loaded
+#[derive(Default)]
+pub struct CollisionMasks {
+    masks: HashMap<(HandleId, usize), CollisionMask>,
+    // Atlases waiting for their image
+    pending: Vec<Handle<TextureAtlas>>,
+}
+
+impl CollisionMasks {
+    pub fn get(
+        &self,
+        atlas: &Handle<TextureAtlas>,
+        sprite: &TextureAtlasSprite,
+    ) -> Option<&CollisionMask> {
+        self.masks.get(&(atlas.id, sprite.index))
+    }
+}
+
+fn update_collision_masks_system(
+    mut events: EventReader<AssetEvent<TextureAtlas>>,
+    atlases: Res<Assets<TextureAtlas>>,
+    imgs: Res<Assets<Image>>,
+    mut masks: ResMut<CollisionMasks>,
+) {
+    for event in events.iter() {
+        match event {
+            AssetEvent::Created { handle } | AssetEvent::Modified { handle } => {
+                masks.pending.push(handle.clone_weak());
+            }
+            AssetEvent::Removed { handle } => {
+                masks.masks.retain(|(atlas, _), _| *atlas != handle.id);
+                masks.pending.retain(|pending| pending != handle);
+            }
+        }
+    }
+    // Atlases and their images finish loading in no particular order
+    for handle in std::mem::take(&mut masks.pending) {
+        let atlas = match atlases.get(&handle) {
+            Some(atlas) => atlas,
+            None => continue,
+        };
+        let img = match imgs.get(&atlas.texture) {
+            Some(img) => img,
+            None => {
+                masks.pending.push(handle);
+                continue;
+            }
+        };
+        for (index, rect) in atlas.textures.iter().enumerate() {
+            let mask = CollisionMask::from_region(
+                img,
+                rect.min.as_uvec2(),
+                (rect.max - rect.min).as_uvec2(),
+            );
+            masks.masks.insert((handle.id, index), mask);
+        }
+    }
+}
+
+// Oriented bounding box of a sprite, used for the Separating Axis Theorem broad test
+struct Obb {
+    center: Vec2,
+    // Local x and y axes of the sprite in world space
+    axes: [Vec2; 2],
+    half_extents: Vec2,
+}
+
+impl Obb {
+    fn new(transform: &Transform, img_size: Vec2) -> Obb {
+        let rotation = rotation_2d(transform);
+        Obb {
+            center: transform.translation.truncate(),
+            axes: [rotation, Vec2::new(-rotation.y, rotation.x)],
+            half_extents: img_size * transform.scale.truncate().abs() / 2.0,
+        }
+    }
+
+    // Half of the length of the box projected on the axis
+    fn projected_radius(&self, axis: Vec2) -> f32 {
+        self.half_extents.x * self.axes[0].dot(axis).abs()
+            + self.half_extents.y * self.axes[1].dot(axis).abs()
+    }
+
+    fn intersects(&self, other: &Obb) -> bool {
+        let distance = other.center - self.center;
+        for axis in self.axes.iter().chain(other.axes.iter()) {
+            if distance.dot(*axis).abs()
+                >= self.projected_radius(*axis) + other.projected_radius(*axis)
+            {
+                return false;
+            }
+        }
+        true
+    }
+
+    // Axis aligned box (min, max) enclosing the rotated box
+    fn aabb(&self) -> (Vec2, Vec2) {
+        let extents = Vec2::new(
+            self.projected_radius(Vec2::X),
+            self.projected_radius(Vec2::Y),
+        );
+        (self.center - extents, self.center + extents)
+    }
+}
+
+// Axis aligned box (min, max) enclosing the rotated and scaled image
+pub fn bounds(transform: &Transform, img_size: Vec2) -> (Vec2, Vec2) {
+    Obb::new(transform, img_size).aabb()
+}
+
+// Axis aligned box (min, max) enclosing the image moving from one translation to the
+// other (rotated and scaled as in the to transform)
+pub fn swept_bounds(from: &Transform, to: &Transform, img_size: Vec2) -> (Vec2, Vec2) {
+    let (min, max) = bounds(to, img_size);
+    let motion = (to.translation - from.translation).truncate();
+    (min.min(min - motion), max.max(max - motion))
+}
+
+// Where and how two images touch
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Contact {
+    // Axis aligned box (min, max) of all the overlapping opaque pixels
+    pub overlap_min: Vec2,
+    pub overlap_max: Vec2,
+    // Center of the first overlapping pixel found (the bottom left most one)
+    pub point: Vec2,
+    // Approximate direction in which a has to move to get out of b
+    pub normal: Vec2,
+}
+
+// Collects the overlapping pixels while walking the intersection of two images
+struct Overlap {
+    stop_at_first: bool,
+    count: u32,
+    first: Vec2,
+    min: Vec2,
+    max: Vec2,
+    sum: Vec2,
+}
+
+impl Overlap {
+    fn new(stop_at_first: bool) -> Overlap {
+        Overlap {
+            stop_at_first,
+            count: 0,
+            first: Vec2::ZERO,
+            min: Vec2::splat(f32::MAX),
+            max: Vec2::splat(f32::MIN),
+            sum: Vec2::ZERO,
+        }
+    }
+
+    // Adds the global pixel (min, max) where both images are opaque
+    fn add(&mut self, min: Vec2, max: Vec2) {
+        let center = (min + max) / 2.0;
+        if self.count == 0 {
+            self.first = center;
+        }
+        self.count += 1;
+        self.min = self.min.min(min);
+        self.max = self.max.max(max);
+        self.sum += center;
+    }
+
+    fn done(&self) -> bool {
+        self.stop_at_first && self.count > 0
+    }
+
+    fn contact(&self, transform_a: &Transform, transform_b: &Transform) -> Option<Contact> {
+        if self.count == 0 {
+            return None;
+        }
+        // Push a away from the middle of the overlap, when that is right in the center
+        // of a push it away from b instead
+        let center_a = transform_a.translation.truncate();
+        let centroid = self.sum / self.count as f32;
+        let mut normal = (center_a - centroid).normalize_or_zero();
+        if normal == Vec2::ZERO {
+            normal = (center_a - transform_b.translation.truncate()).normalize_or_zero();
+        }
+        Some(Contact {
+            overlap_min: self.min,
+            overlap_max: self.max,
+            point: self.first,
+            normal,
+        })
+    }
+}
+
+// Same as contact but image a moves from from_a to transform_a and the contact is
+// from the first place on the way where it touches b. The way is walked in one pixel
+// steps.
+pub fn contact_swept(
+    from_a: &Transform,
+    transform_a: &Transform,
+    mask_a: &CollisionMask,
+    transform_b: &Transform,
+    mask_b: &CollisionMask,
+) -> Option<Contact> {
+    let (swept_min, swept_max) = swept_bounds(from_a, transform_a, mask_a.size());
+    let (b_min, b_max) = bounds(transform_b, mask_b.size());
+    if swept_min.x >= b_max.x
+        || swept_max.x <= b_min.x
+        || swept_min.y >= b_max.y
+        || swept_max.y <= b_min.y
+    {
+        return None;
+    }
+
+    let motion = transform_a.translation - from_a.translation;
+    let steps = motion.truncate().length().ceil().max(1.0);
+    let mut step = 0.0;
+    while step <= steps {
+        let mut stepped_a = *transform_a;
+        stepped_a.translation = from_a.translation + motion * (step / steps);
+        if collide(&stepped_a, mask_a, transform_b, mask_b) {
+            return contact(&stepped_a, mask_a, transform_b, mask_b);
+        }
+        step += 1.0;
+    }
+    None
+}
+
 pub fn collide(
-    //commands: &mut Commands,
     transform_a: &Transform,
-    img_a: &Image,
+    mask_a: &CollisionMask,
     transform_b: &Transform,
-    img_b: &Image,
+    mask_b: &CollisionMask,
 ) -> bool {
-    let a_min = transform_a.translation.truncate() - img_a.size() / 2.0;
-    let a_max = transform_a.translation.truncate() + img_a.size() / 2.0;
-
-    let b_min = transform_b.translation.truncate() - img_b.size() / 2.0;
-    let b_max = transform_b.translation.truncate() + img_b.size() / 2.0;
-
-    // check to see if the two rectangles are intersecting
-    if a_min.x < b_max.x && a_max.x > b_min.x && a_min.y < b_max.y && a_max.y > b_min.y {
-        let intersect_min = a_min.max(b_min);
-        let intersect_max = a_max.min(b_max);
-
-        //debug::spawn_square(commands, intersect_min, intersect_max, Color::rgb(0.0, 0.0, 0.0));
-        //debug::spawn_square(commands, a_min, a_max, Color::rgb(1.0, 0.0, 0.0));
-        //debug::spawn_square(commands, b_min, b_max, Color::rgb(0.0, 1.0, 0.0));
-
-        // Since we want to index from top left corner (that is where image data starts)
-        // we go from smallest x to largest (moving left to right) and from largest y to
-        // smallest (moving top to bottom)
-        // to reflect that in the translation from global to local coords..
-        let mut y = intersect_min.y;
-        while y < intersect_max.y {
-            let mut x = intersect_min.x;
-            while x < intersect_max.x {
-                let mut a_local_index: Vec2 =
-                    global_to_local(Vec2::new(x, y), transform_a, img_a.size());
-                let mut b_local_index: Vec2 =
-                    global_to_local(Vec2::new(x, y), transform_b, img_b.size());
-                a_local_index = rotate_index(a_local_index, transform_a, img_a.size());
-                b_local_index = rotate_index(b_local_index, transform_b, img_b.size());
-                let a_index = (a_local_index.x.floor() * 4.0
-                    + ((a_local_index.y.floor()) * (img_a.size().x.floor() * 4.0)))
-                    as usize
-                    + 3;
-                let b_index = (b_local_index.x.floor() * 4.0
-                    + ((b_local_index.y.floor()) * (img_b.size().x.floor() * 4.0)))
-                    as usize
-                    + 3;
-
-                let color_a = img_a.data[a_index];
-                let color_b = img_b.data[b_index];
-                if color_a >= 1 && color_b >= 1 {
-                    //println!("collided at {:?},{:?}", x, y);
-                    //debug::spawn_square(
-                    //    commands,
-                    //    Vec2::new(x, y),
-                    //    Vec2::new(x, y),
-                    //    Color::rgb(0.0, 0.0, 10.0),
-                    //);
-                    //ret = true;
-                    return true;
+    let mut overlap = Overlap::new(true);
+    find_overlap(transform_a, mask_a, transform_b, mask_b, &mut overlap);
+    overlap.count > 0
+}
+
+pub fn contact(
+    transform_a: &Transform,
+    mask_a: &CollisionMask,
+    transform_b: &Transform,
+    mask_b: &CollisionMask,
+) -> Option<Contact> {
+    let mut overlap = Overlap::new(false);
+    find_overlap(transform_a, mask_a, transform_b, mask_b, &mut overlap);
+    overlap.contact(transform_a, transform_b)
+}
+
+fn find_overlap(
+    //commands: &mut Commands,
+    transform_a: &Transform,
+    mask_a: &CollisionMask,
+    transform_b: &Transform,
+    mask_b: &CollisionMask,
+    overlap: &mut Overlap,
+) {
+    let obb_a = Obb::new(transform_a, mask_a.size());
+    let obb_b = Obb::new(transform_b, mask_b.size());
+
+    if !obb_a.intersects(&obb_b) {
+        return;
+    }
+
+    let (a_min, a_max) = obb_a.aabb();
+    let (b_min, b_max) = obb_b.aabb();
+    let intersect_min = a_min.max(b_min);
+    let intersect_max = a_max.min(b_max);
+
+    //debug::spawn_square(commands, intersect_min, intersect_max, Color::rgb(0.0, 0.0, 0.0));
+    //debug::spawn_square(commands, a_min, a_max, Color::rgb(1.0, 0.0, 0.0));
+    //debug::spawn_square(commands, b_min, b_max, Color::rgb(0.0, 1.0, 0.0));
+
+    // Walk the intersection in one pixel steps and sample in the middle of each step
+    // (the last step in a row/column can be shorter). The sample point is mapped back
+    // through the inverse transform of each sprite to find the pixel it lands on.
+    let row_directions = row_direction(transform_a).zip(row_direction(transform_b));
+    let mut y = intersect_min.y;
+    while y < intersect_max.y {
+        let row = (y, (y + 1.0).min(intersect_max.y));
+        match row_directions {
+            Some(directions) => overlap_row_words(
+                row,
+                intersect_min.x,
+                intersect_max.x,
+                (transform_a, mask_a),
+                (transform_b, mask_b),
+                directions,
+                overlap,
+            ),
+            None => overlap_row_pixels(
+                row,
+                intersect_min.x,
+                intersect_max.x,
+                (transform_a, mask_a),
+                (transform_b, mask_b),
+                overlap,
+            ),
+        };
+        if overlap.done() {
+            //println!("collided at {:?}", y);
+            return;
+        }
+        y += 1.0;
+    }
+}
+
+fn overlap_row_pixels(
+    (y, y_end): (f32, f32),
+    from_x: f32,
+    to_x: f32,
+    (transform_a, mask_a): (&Transform, &CollisionMask),
+    (transform_b, mask_b): (&Transform, &CollisionMask),
+    overlap: &mut Overlap,
+) {
+    let mut x = from_x;
+    while x < to_x {
+        let x_end = (x + 1.0).min(to_x);
+        let sample = Vec2::new((x + x_end) / 2.0, (y + y_end) / 2.0);
+        if is_opaque(sample, transform_a, mask_a) && is_opaque(sample, transform_b, mask_b) {
+            //debug::spawn_square(
+            //    commands,
+            //    sample,
+            //    sample,
+            //    Color::rgb(0.0, 0.0, 10.0),
+            //);
+            overlap.add(Vec2::new(x, y), Vec2::new(x_end, y_end));
+            if overlap.done() {
+                return;
+            }
+        }
+        x += 1.0;
+    }
+}
+
+// When neither image is rotated nor scaled (other than flipped) a row of global samples
+// maps to a continuous run of pixels in both masks so whole words can be compared at once.
+fn overlap_row_words(
+    (y, y_end): (f32, f32),
+    from_x: f32,
+    to_x: f32,
+    (transform_a, mask_a): (&Transform, &CollisionMask),
+    (transform_b, mask_b): (&Transform, &CollisionMask),
+    (direction_a, direction_b): (i32, i32),
+    overlap: &mut Overlap,
+) {
+    // Only whole steps are a continuous run, the shorter last step is sampled on its own
+    let full_steps = (to_x - from_x).floor() as usize;
+    if full_steps > 0 {
+        let sample_y = (y + y_end) / 2.0;
+        let first = Vec2::new(from_x + 0.5, sample_y);
+        let last = Vec2::new(from_x + full_steps as f32 - 0.5, sample_y);
+        match (
+            pixel_index(first, transform_a, mask_a.size()),
+            pixel_index(first, transform_b, mask_b.size()),
+            pixel_index(last, transform_a, mask_a.size()),
+            pixel_index(last, transform_b, mask_b.size()),
+        ) {
+            (Some(start_a), Some(start_b), Some(last_a), Some(last_b))
+                if offset_column(start_a.x, direction_a, full_steps - 1) == last_a.x as usize
+                    && offset_column(start_b.x, direction_b, full_steps - 1)
+                        == last_b.x as usize =>
+            {
+                let mut step = 0;
+                while step < full_steps {
+                    let len = (full_steps - step).min(MASK_WORD_BITS);
+                    let bits_a = mask_a.row_run(
+                        start_a.y as usize,
+                        offset_column(start_a.x, direction_a, step),
+                        direction_a,
+                        len,
+                    );
+                    let bits_b = mask_b.row_run(
+                        start_b.y as usize,
+                        offset_column(start_b.x, direction_b, step),
+                        direction_b,
+                        len,
+                    );
+                    let mut hits = bits_a & bits_b;
+                    while hits != 0 {
+                        let x = from_x + (step + hits.trailing_zeros() as usize) as f32;
+                        overlap.add(Vec2::new(x, y), Vec2::new(x + 1.0, y_end));
+                        if overlap.done() {
+                            return;
+                        }
+                        // Clear the lowest hit
+                        hits &= hits - 1;
+                    }
+                    step += len;
                 }
-                x += 1.0;
             }
-            y += 1.0;
+            // The row misses one of the images or is at its very edge (so the run could
+            // be off by one pixel due to rounding)
+            _ => {
+                return overlap_row_pixels(
+                    (y, y_end),
+                    from_x,
+                    to_x,
+                    (transform_a, mask_a),
+                    (transform_b, mask_b),
+                    overlap,
+                )
+            }
         }
+    }
 
-        return false;
+    let last_step = from_x + full_steps as f32;
+    if last_step < to_x {
+        overlap_row_pixels(
+            (y, y_end),
+            last_step,
+            to_x,
+            (transform_a, mask_a),
+            (transform_b, mask_b),
+            overlap,
+        );
     }
+}
 
-    return false;
+fn offset_column(start: f32, direction: i32, step: usize) -> usize {
+    (start as i32 + direction * step as i32) as usize
 }
 
-// It takes top left indexing and returns top left indexing
-fn rotate_index(index: Vec2, img_transform: &Transform, img_size: Vec2) -> Vec2 {
-    if img_transform.rotation.to_scaled_axis().z > 3.14 {
-        return img_size - index - Vec2::new(1.0, 1.0);
+// Direction in which the pixel columns of the image go along global x, None when the
+// image is rotated or scaled in a way that doesn't map global rows to image rows
+fn row_direction(transform: &Transform) -> Option<i32> {
+    let rotation = rotation_2d(transform);
+    let scale = transform.scale.truncate();
+    if rotation.y != 0.0 || scale.abs() != Vec2::ONE {
+        return None;
+    }
+    if (rotation.x < 0.0) != (scale.x < 0.0) {
+        Some(-1)
+    } else {
+        Some(1)
+    }
+}
+
+// Checks the alpha of the pixel under the global point, points outside of the image
+// (for example in the corners of rotated images bounding box) are transparent
+fn is_opaque(global: Vec2, img_transform: &Transform, mask: &CollisionMask) -> bool {
+    match pixel_index(global, img_transform, mask.size()) {
+        Some(index) => mask.is_opaque(index.x as usize, index.y as usize),
+        None => false,
+    }
+}
+
+// Top left index of the image pixel under the global point, None when outside of the image
+fn pixel_index(global: Vec2, img_transform: &Transform, img_size: Vec2) -> Option<Vec2> {
+    // global_to_local works with bottom left corners of global pixels, shift the sample
+    // so that whole local indexes are centers of the image pixels
+    let local_index = global_to_local(global - Vec2::new(0.5, 0.5), img_transform, img_size);
+    let index = (rotate_index(local_index, img_transform, img_size) + Vec2::new(0.5, 0.5)).floor();
+    if index.x < 0.0 || index.y < 0.0 || index.x >= img_size.x || index.y >= img_size.y {
+        return None;
+    }
+    Some(index)
+}
+
+// Z rotation of the transform as (cos, sin), quarter turns are snapped to exact values
+fn rotation_2d(transform: &Transform) -> Vec2 {
+    let rotation = (transform.rotation * Vec3::X).truncate().normalize();
+    let snapped = rotation.round();
+    if (rotation - snapped).length() < ROTATION_SNAP_EPSILON {
+        snapped
     } else {
+        rotation
+    }
+}
+
+// It takes top left indexing of the unrotated and unscaled image and returns top left
+// indexing of the image rotated by the Z rotation and scaled by the scale of img_transform
+// (both around image center). Negative scale mirrors the image.
+fn rotate_index(index: Vec2, img_transform: &Transform, img_size: Vec2) -> Vec2 {
+    let rotation = rotation_2d(img_transform);
+    let scale = img_transform.scale.truncate();
+    if rotation == Vec2::X && scale == Vec2::ONE {
         return index;
     }
+
+    let center = (img_size - Vec2::new(1.0, 1.0)) / 2.0;
+    // Flip to bottom up y so the rotation goes the same way as in the world
+    let offset = Vec2::new(index.x - center.x, center.y - index.y);
+    let unrotated = Vec2::new(
+        rotation.x * offset.x + rotation.y * offset.y,
+        rotation.x * offset.y - rotation.y * offset.x,
+    );
+    let unscaled = unrotated / scale;
+    Vec2::new(center.x + unscaled.x, center.y - unscaled.y)
 }
 
 // It takes global bottom left index and returns local top left indexing
@@ -93,6 +727,9 @@ fn global_to_local(global: Vec2, img_trans: &Transform, img_size: Vec2) -> Vec2
 mod tests {
     use super::*;
 
+    // Walls are checked against the map tile index now, no collider uses this layer
+    const LAYER_WALL: u32 = 1 << 31;
+
     fn generate_image_l() -> Image {
         //image should be:
         // 1, 1, 1, 1,
@@ -162,12 +799,55 @@ mod tests {
         )
     }
 
+    fn generate_image_pixel() -> Image {
+        //image should be:
+        // 1
+        let vec = vec![1, 0, 0, 1];
+        bevy::render::texture::Image::new(
+            bevy::render::render_resource::Extent3d {
+                width: 1,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            bevy::render::render_resource::TextureDimension::D2,
+            vec,
+            bevy::render::render_resource::TextureFormat::Rgba8Uint,
+        )
+    }
+
+    fn generate_image_row(alphas: &[u8]) -> Image {
+        let vec = alphas.iter().flat_map(|alpha| [1, 0, 0, *alpha]).collect();
+        bevy::render::texture::Image::new(
+            bevy::render::render_resource::Extent3d {
+                width: alphas.len() as u32,
+                height: 1,
+                depth_or_array_layers: 1,
+            },
+            bevy::render::render_resource::TextureDimension::D2,
+            vec,
+            bevy::render::render_resource::TextureFormat::Rgba8Uint,
+        )
+    }
+
+    #[test]
+    fn mask_from_region_covers_just_the_region() {
+        let img = generate_image_o();
+        let middle = CollisionMask::from_region(&img, UVec2::new(1, 1), UVec2::new(2, 2));
+        assert_eq!(Vec2::new(2.0, 2.0), middle.size());
+        for (x, y) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            assert!(middle.is_opaque(x, y));
+        }
+        let corner = CollisionMask::from_region(&img, UVec2::new(2, 0), UVec2::new(2, 2));
+        assert!(!corner.is_opaque(1, 0));
+        assert!(corner.is_opaque(0, 1));
+    }
+
     #[test]
     fn collide_test_1() {
         let mut trans_a = Transform::from_xyz(4.0, 4.0, 0.0);
         let trans_b = Transform::from_xyz(4.0, 4.0, 0.0);
-        let img_a = generate_image();
-        let img_b = generate_image();
+        let img_a = CollisionMask::from_image(&generate_image());
+        let img_b = CollisionMask::from_image(&generate_image());
 
         assert_eq!(true, collide(&trans_a, &img_a, &trans_b, &img_b));
 
@@ -221,8 +901,8 @@ mod tests {
     fn collide_test_2() {
         let mut trans_a = Transform::from_xyz(4.0, 4.0, 0.0);
         let trans_b = Transform::from_xyz(4.0, 4.0, 0.0);
-        let img_a = generate_image_l();
-        let img_b = generate_image_l();
+        let img_a = CollisionMask::from_image(&generate_image_l());
+        let img_b = CollisionMask::from_image(&generate_image_l());
 
         assert_eq!(true, collide(&trans_a, &img_a, &trans_b, &img_b));
 
@@ -262,8 +942,8 @@ mod tests {
         let mut trans_a = Transform::from_xyz(4.0, 4.0, 0.0);
         let mut trans_b = Transform::from_xyz(4.0, 4.0, 0.0);
         trans_b.rotate_z(f32::to_radians(180.0));
-        let img_a = generate_image();
-        let img_b = generate_image_l();
+        let img_a = CollisionMask::from_image(&generate_image());
+        let img_b = CollisionMask::from_image(&generate_image_l());
 
         assert_eq!(true, collide(&trans_a, &img_a, &trans_b, &img_b));
 
@@ -285,8 +965,8 @@ mod tests {
         let mut trans_a = Transform::from_xyz(4.0, 4.0, 0.0);
         let mut trans_b = Transform::from_xyz(4.0, 4.0, 0.0);
         trans_b.rotate_z(f32::to_radians(180.0));
-        let img_a = generate_image_o();
-        let img_b = generate_image_o();
+        let img_a = CollisionMask::from_image(&generate_image_o());
+        let img_b = CollisionMask::from_image(&generate_image_o());
 
         assert_eq!(true, collide(&trans_a, &img_a, &trans_b, &img_b));
 
@@ -308,8 +988,8 @@ mod tests {
         let mut trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
         let mut trans_b = Transform::from_xyz(0.0, 0.0, 0.0);
         trans_b.rotate_z(f32::to_radians(180.0));
-        let img_a = generate_image_o();
-        let img_b = generate_image_o();
+        let img_a = CollisionMask::from_image(&generate_image_o());
+        let img_b = CollisionMask::from_image(&generate_image_o());
 
         assert_eq!(true, collide(&trans_a, &img_a, &trans_b, &img_b));
 
@@ -337,8 +1017,8 @@ mod tests {
         let mut trans_a = Transform::from_xyz(-4.0, -4.0, 0.0);
         let mut trans_b = Transform::from_xyz(-4.0, -4.0, 0.0);
         trans_b.rotate_z(f32::to_radians(180.0));
-        let img_a = generate_image_o();
-        let img_b = generate_image_o();
+        let img_a = CollisionMask::from_image(&generate_image_o());
+        let img_b = CollisionMask::from_image(&generate_image_o());
 
         assert_eq!(true, collide(&trans_a, &img_a, &trans_b, &img_b));
 
@@ -361,6 +1041,237 @@ mod tests {
         assert_eq!(false, collide(&trans_a, &img_a, &trans_b, &img_b));
     }
 
+    #[test]
+    fn collide_test_rotated_90() {
+        // Rotated 90 degrees counter clockwise the L image becomes:
+        // 1, 1, 1, 0,
+        // 1, 1, 1, 0,
+        // 1, 0, 0, 0,
+        // 1, 1, 1, 1
+        let mut trans_a = Transform::from_xyz(4.0, 4.0, 0.0);
+        trans_a.rotate_z(f32::to_radians(90.0));
+        let img_a = CollisionMask::from_image(&generate_image_l());
+        let img_b = CollisionMask::from_image(&generate_image_pixel());
+
+        let mut trans_b = Transform::from_xyz(2.5, 5.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(5.5, 5.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(5.5, 3.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(3.5, 3.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(4.5, 3.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(5.5, 2.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(3.5, 4.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        // Rotating the other way round gives a different shape
+        trans_a = Transform::from_xyz(4.0, 4.0, 0.0);
+        trans_a.rotate_z(f32::to_radians(-90.0));
+        trans_b = Transform::from_xyz(2.5, 5.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(2.5, 2.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+    }
+
+    #[test]
+    fn collide_test_rotated_45() {
+        // Full image rotated by 45 degrees is a diamond reaching sqrt(8) from its center
+        let mut trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
+        trans_a.rotate_z(f32::to_radians(45.0));
+        let img_a = CollisionMask::from_image(&generate_image());
+        let img_b = CollisionMask::from_image(&generate_image_pixel());
+
+        // Tip of the diamond is outside of the unrotated image
+        let mut trans_b = Transform::from_xyz(2.5, 0.0, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+        assert!(!collide(
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            &img_a,
+            &trans_b,
+            &img_b
+        ));
+
+        trans_b = Transform::from_xyz(0.0, -2.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        // Corner of the unrotated image is cut off
+        trans_b = Transform::from_xyz(2.2, 2.2, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+        assert!(collide(
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            &img_a,
+            &trans_b,
+            &img_b
+        ));
+
+        trans_b = Transform::from_xyz(-1.0, 1.0, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+    }
+
+    #[test]
+    fn collide_test_both_rotated_45() {
+        let mut trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
+        trans_a.rotate_z(f32::to_radians(45.0));
+        let img_a = CollisionMask::from_image(&generate_image());
+        let img_b = CollisionMask::from_image(&generate_image());
+
+        // Tips of the diamonds overlap
+        let mut trans_b = Transform::from_xyz(4.5, 0.0, 0.0);
+        trans_b.rotate_z(f32::to_radians(45.0));
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+        assert!(!collide(
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            &img_a,
+            &Transform::from_xyz(4.5, 0.0, 0.0),
+            &img_b
+        ));
+
+        trans_b = Transform::from_xyz(6.0, 0.0, 0.0);
+        trans_b.rotate_z(f32::to_radians(45.0));
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        // Separated only along the diagonal axis of the diamonds
+        trans_b = Transform::from_xyz(3.0, 3.0, 0.0);
+        trans_b.rotate_z(f32::to_radians(45.0));
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+        assert!(collide(
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            &img_a,
+            &Transform::from_xyz(3.0, 3.0, 0.0),
+            &img_b
+        ));
+    }
+
+    #[test]
+    fn collide_test_upscaled() {
+        // Every pixel of the L image covers 2x2 global pixels
+        let mut trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
+        trans_a.scale = Vec3::new(2.0, 2.0, 1.0);
+        let img_a = CollisionMask::from_image(&generate_image_l());
+        let img_b = CollisionMask::from_image(&generate_image_pixel());
+
+        let mut trans_b = Transform::from_xyz(2.5, 2.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+        assert!(!collide(
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            &img_a,
+            &trans_b,
+            &img_b
+        ));
+
+        trans_b = Transform::from_xyz(-3.5, 0.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(-1.5, 0.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(-0.5, -1.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(1.5, -3.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(-3.5, -3.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(4.5, 0.0, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+    }
+
+    #[test]
+    fn collide_test_downscaled() {
+        let mut trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
+        trans_a.scale = Vec3::new(0.5, 0.5, 1.0);
+        let img_a = CollisionMask::from_image(&generate_image());
+        let img_b = CollisionMask::from_image(&generate_image_pixel());
+
+        let mut trans_b = Transform::from_xyz(1.5, 0.0, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+        assert!(collide(
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            &img_a,
+            &trans_b,
+            &img_b
+        ));
+
+        trans_b = Transform::from_xyz(0.5, 0.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(-0.6, -1.3, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(-0.6, -1.6, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+    }
+
+    #[test]
+    fn collide_test_non_uniform_scale() {
+        let mut trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
+        trans_a.scale = Vec3::new(2.0, 0.5, 1.0);
+        let img_a = CollisionMask::from_image(&generate_image());
+        let img_b = CollisionMask::from_image(&generate_image_pixel());
+
+        let mut trans_b = Transform::from_xyz(3.5, 0.0, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(0.0, 1.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        // Scaled along the local axes, so rotating swaps the stretched side
+        trans_a.rotate_z(f32::to_radians(90.0));
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(3.5, 0.0, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+    }
+
+    #[test]
+    fn collide_test_mirrored() {
+        // Mirrored along x the L image becomes:
+        // 1, 1, 1, 1,
+        // 1, 1, 0, 1,
+        // 1, 1, 0, 1,
+        // 0, 0, 0, 1
+        let mut trans_a = Transform::from_xyz(4.0, 4.0, 0.0);
+        trans_a.scale = Vec3::new(-1.0, 1.0, 1.0);
+        let img_a = CollisionMask::from_image(&generate_image_l());
+        let img_b = CollisionMask::from_image(&generate_image_pixel());
+
+        let mut trans_b = Transform::from_xyz(3.5, 4.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(4.5, 4.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(2.5, 2.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(5.5, 2.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        // Mirrored along y
+        trans_a.scale = Vec3::new(1.0, -1.0, 1.0);
+        trans_b = Transform::from_xyz(2.5, 5.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(4.5, 5.5, 0.0);
+        assert!(!collide(&trans_a, &img_a, &trans_b, &img_b));
+
+        trans_b = Transform::from_xyz(4.5, 2.5, 0.0);
+        assert!(collide(&trans_a, &img_a, &trans_b, &img_b));
+    }
+
     //TODO(amatej): add collide test with float indexes, such as 4.321, 9.4324
 
     #[test]
@@ -479,4 +1390,361 @@ mod tests {
             rotate_index(Vec2::new(3.0, 1.0), &upside_down_trans, Vec2::new(4.0, 4.0))
         );
     }
+
+    #[test]
+    fn rotate_index_quarter_turn() {
+        let mut quarter_trans = Transform::from_xyz(0.0, 0.0, 0.0);
+        quarter_trans.rotate_z(f32::to_radians(90.0));
+        assert_eq!(
+            Vec2::new(3.0, 0.0),
+            rotate_index(Vec2::new(0.0, 0.0), &quarter_trans, Vec2::new(4.0, 4.0))
+        );
+        assert_eq!(
+            Vec2::new(0.0, 0.0),
+            rotate_index(Vec2::new(0.0, 3.0), &quarter_trans, Vec2::new(4.0, 4.0))
+        );
+        assert_eq!(
+            Vec2::new(2.0, 1.0),
+            rotate_index(Vec2::new(1.0, 1.0), &quarter_trans, Vec2::new(4.0, 4.0))
+        );
+    }
+
+    #[test]
+    fn rotate_index_scaled() {
+        let mut scaled_trans = Transform::from_xyz(0.0, 0.0, 0.0);
+        scaled_trans.scale = Vec3::new(2.0, 2.0, 1.0);
+        assert_eq!(
+            Vec2::new(0.75, 0.75),
+            rotate_index(Vec2::new(0.0, 0.0), &scaled_trans, Vec2::new(4.0, 4.0))
+        );
+        assert_eq!(
+            Vec2::new(1.5, 1.5),
+            rotate_index(Vec2::new(1.5, 1.5), &scaled_trans, Vec2::new(4.0, 4.0))
+        );
+
+        scaled_trans.scale = Vec3::new(-1.0, 1.0, 1.0);
+        assert_eq!(
+            Vec2::new(3.0, 1.0),
+            rotate_index(Vec2::new(0.0, 1.0), &scaled_trans, Vec2::new(4.0, 4.0))
+        );
+    }
+
+    #[test]
+    fn mask_from_image() {
+        let mask = CollisionMask::from_image(&generate_image_l());
+        assert_eq!(Vec2::new(4.0, 4.0), mask.size());
+        assert_eq!(0b1111, mask.row_bits(0, 0, 4));
+        assert_eq!(0b1101, mask.row_bits(1, 0, 4));
+        assert_eq!(0b1101, mask.row_bits(2, 0, 4));
+        assert_eq!(0b0001, mask.row_bits(3, 0, 4));
+        assert!(mask.is_opaque(0, 3));
+        assert!(!mask.is_opaque(1, 3));
+    }
+
+    #[test]
+    fn mask_row_bits_across_words() {
+        let mut alphas = vec![0; 70];
+        alphas[0] = 255;
+        alphas[63] = 255;
+        alphas[64] = 1;
+        alphas[69] = 255;
+        let mask = CollisionMask::from_image(&generate_image_row(&alphas));
+        assert_eq!(2, mask.words_per_row);
+        assert_eq!(1 | 1 << 63, mask.row_bits(0, 0, 64));
+        assert_eq!(0b11, mask.row_bits(0, 63, 2));
+        assert_eq!(0b11 | 1 << 6, mask.row_bits(0, 63, 7));
+        assert_eq!(1 | 1 << 5, mask.row_bits(0, 64, 6));
+        assert_eq!(1 << 62 | 1 << 61, mask.row_bits(0, 2, 64));
+    }
+
+    #[test]
+    fn mask_row_run_backwards() {
+        let mask = CollisionMask::from_image(&generate_image_row(&[1, 1, 0, 0, 1]));
+        assert_eq!(0b10011, mask.row_run(0, 0, 1, 5));
+        assert_eq!(0b11001, mask.row_run(0, 4, -1, 5));
+        assert_eq!(0b001, mask.row_run(0, 4, -1, 3));
+        assert_eq!(0b011, mask.row_run(0, 1, -1, 2));
+    }
+
+    #[test]
+    fn collide_test_wide_images() {
+        // Two 100 pixels wide rows with a single opaque pixel each
+        let mut alphas = vec![0; 100];
+        alphas[80] = 1;
+        let mask_a = CollisionMask::from_image(&generate_image_row(&alphas));
+        alphas[80] = 0;
+        alphas[10] = 1;
+        let mask_b = CollisionMask::from_image(&generate_image_row(&alphas));
+
+        let trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
+        let mut trans_b = Transform::from_xyz(70.0, 0.0, 0.0);
+        assert!(collide(&trans_a, &mask_a, &trans_b, &mask_b));
+
+        trans_b = Transform::from_xyz(69.0, 0.0, 0.0);
+        assert!(!collide(&trans_a, &mask_a, &trans_b, &mask_b));
+
+        trans_b = Transform::from_xyz(70.3, 0.2, 0.0);
+        assert!(collide(&trans_a, &mask_a, &trans_b, &mask_b));
+
+        // Upside down the opaque pixel of b is at column 89
+        trans_b = Transform::from_xyz(-9.0, 0.0, 0.0);
+        trans_b.rotate_z(f32::to_radians(180.0));
+        assert!(collide(&trans_a, &mask_a, &trans_b, &mask_b));
+
+        trans_b = Transform::from_xyz(-8.0, 0.0, 0.0);
+        trans_b.rotate_z(f32::to_radians(180.0));
+        assert!(!collide(&trans_a, &mask_a, &trans_b, &mask_b));
+    }
+
+    #[test]
+    fn colliders_interact_by_layer_and_mask() {
+        let shot = Collider {
+            layer: LAYER_SHOT,
+            mask: LAYER_ENEMY,
+        };
+        let enemy = Collider {
+            layer: LAYER_ENEMY,
+            mask: LAYER_PLAYER,
+        };
+        let wall = Collider {
+            layer: LAYER_WALL,
+            mask: 0,
+        };
+        assert!(shot.interacts_with(&enemy));
+        assert!(enemy.interacts_with(&shot));
+        assert!(!shot.interacts_with(&wall));
+        assert!(!wall.interacts_with(&wall));
+    }
+
+    #[test]
+    fn detect_collisions_sends_each_pair_once() {
+        let mut world = World::new();
+        let handle: Handle<TextureAtlas> = Handle::weak(HandleId::random::<TextureAtlas>());
+        let mut masks = CollisionMasks::default();
+        masks
+            .masks
+            .insert((handle.id, 0), CollisionMask::from_image(&generate_image()));
+
+        let mut spawn = |x: f32, layer: u32, mask: u32| {
+            world
+                .spawn()
+                .insert(Transform::from_xyz(x, 0.0, 0.0))
+                .insert(handle.clone())
+                .insert(TextureAtlasSprite::new(0))
+                .insert(Collider { layer, mask })
+                .id()
+        };
+        // Shot overlapping two enemies, enemies overlap each other too
+        let shot = spawn(0.0, LAYER_SHOT, LAYER_ENEMY);
+        let enemy_a = spawn(-2.0, LAYER_ENEMY, LAYER_SHOT);
+        let enemy_b = spawn(2.0, LAYER_ENEMY, LAYER_SHOT);
+        // Walls don't look for each other
+        spawn(100.0, LAYER_WALL, 0);
+        spawn(101.0, LAYER_WALL, 0);
+
+        let mut grid = broadphase::SpatialGrid::<Collider>::default();
+        for (entity, transform) in world.query::<(Entity, &Transform)>().iter(&world) {
+            grid.insert(entity, transform, Vec2::new(4.0, 4.0));
+        }
+        world.insert_resource(masks);
+        world.insert_resource(grid);
+        world.insert_resource(Events::<CollisionEvent>::default());
+
+        let mut stage = SystemStage::single(detect_collisions_system);
+        stage.run(&mut world);
+
+        let events = world.resource::<Events<CollisionEvent>>();
+        let mut pairs: Vec<(Entity, Entity)> = events
+            .get_reader()
+            .iter(events)
+            .map(|event| event.ordered(|e| e == shot))
+            .collect();
+        pairs.sort();
+        assert_eq!(vec![(shot, enemy_a), (shot, enemy_b)], pairs);
+    }
+
+    #[test]
+    fn contact_swept_fast_shot_hits_thin_target() {
+        // 4 pixels tall target and a shot moving 64 pixels per frame
+        let target = CollisionMask::from_image(&generate_image());
+        let shot = CollisionMask::from_image(&generate_image_pixel());
+        let target_trans = Transform::from_xyz(0.0, 0.0, 0.0);
+        let from = Transform::from_xyz(0.5, -32.5, 0.0);
+        let to = Transform::from_xyz(0.5, 31.5, 0.0);
+
+        // Neither end of the move touches the target
+        assert!(!collide(&from, &shot, &target_trans, &target));
+        assert!(!collide(&to, &shot, &target_trans, &target));
+        assert!(contact_swept(&from, &to, &shot, &target_trans, &target).is_some());
+
+        // Flying next to the target
+        let from = Transform::from_xyz(10.5, -32.5, 0.0);
+        let to = Transform::from_xyz(10.5, 31.5, 0.0);
+        assert!(contact_swept(&from, &to, &shot, &target_trans, &target).is_none());
+
+        // Not there yet
+        let from = Transform::from_xyz(0.5, -96.5, 0.0);
+        let to = Transform::from_xyz(0.5, -32.5, 0.0);
+        assert!(contact_swept(&from, &to, &shot, &target_trans, &target).is_none());
+    }
+
+    #[test]
+    fn contact_swept_without_motion_is_contact() {
+        let img_a = CollisionMask::from_image(&generate_image_o());
+        let img_b = CollisionMask::from_image(&generate_image_o());
+        let trans_b = Transform::from_xyz(4.0, 4.0, 0.0);
+        for x in [2.0, 5.0, 6.0] {
+            let trans_a = Transform::from_xyz(x, 4.0, 0.0);
+            assert_eq!(
+                contact(&trans_a, &img_a, &trans_b, &img_b),
+                contact_swept(&trans_a, &trans_a, &img_a, &trans_b, &img_b)
+            );
+        }
+    }
+
+    #[test]
+    fn detect_collisions_sweeps_continuous_colliders() {
+        let mut world = World::new();
+        // Both are regions of the same atlas
+        let atlas: Handle<TextureAtlas> = Handle::weak(HandleId::random::<TextureAtlas>());
+        let mut masks = CollisionMasks::default();
+        masks
+            .masks
+            .insert((atlas.id, 0), CollisionMask::from_image(&generate_image()));
+        masks.masks.insert(
+            (atlas.id, 1),
+            CollisionMask::from_image(&generate_image_pixel()),
+        );
+
+        let target = world
+            .spawn()
+            .insert(Transform::from_xyz(0.0, 0.0, 0.0))
+            .insert(atlas.clone())
+            .insert(TextureAtlasSprite::new(0))
+            .insert(Collider {
+                layer: LAYER_ENEMY,
+                mask: LAYER_SHOT,
+            })
+            .id();
+        let shot_trans = Transform::from_xyz(0.5, 31.5, 0.0);
+        let shot = world
+            .spawn()
+            .insert(shot_trans)
+            .insert(atlas.clone())
+            .insert(TextureAtlasSprite::new(1))
+            .insert(Collider {
+                layer: LAYER_SHOT,
+                mask: LAYER_ENEMY,
+            })
+            .insert(ContinuousCollision::new(&Transform::from_xyz(
+                0.5, -32.5, 0.0,
+            )))
+            .id();
+
+        let mut grid = broadphase::SpatialGrid::<Collider>::default();
+        grid.insert(
+            target,
+            &Transform::from_xyz(0.0, 0.0, 0.0),
+            Vec2::new(4.0, 4.0),
+        );
+        grid.insert(shot, &shot_trans, Vec2::new(1.0, 1.0));
+        world.insert_resource(masks);
+        world.insert_resource(grid);
+        world.insert_resource(Events::<CollisionEvent>::default());
+
+        let mut stage = SystemStage::single(detect_collisions_system);
+        stage.run(&mut world);
+
+        let events = world.resource::<Events<CollisionEvent>>();
+        let pairs: Vec<(Entity, Entity)> = events
+            .get_reader()
+            .iter(events)
+            .map(|event| event.ordered(|e| e == shot))
+            .collect();
+        assert_eq!(vec![(shot, target)], pairs);
+        // The next sweep starts where the shot is now
+        assert_eq!(
+            shot_trans,
+            world.get::<ContinuousCollision>(shot).unwrap().previous
+        );
+    }
+
+    #[test]
+    fn contact_side_by_side() {
+        let img_a = CollisionMask::from_image(&generate_image());
+        let img_b = CollisionMask::from_image(&generate_image());
+        let trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
+        let trans_b = Transform::from_xyz(3.0, 0.0, 0.0);
+
+        assert_eq!(
+            Some(Contact {
+                overlap_min: Vec2::new(1.0, -2.0),
+                overlap_max: Vec2::new(2.0, 2.0),
+                point: Vec2::new(1.5, -1.5),
+                normal: Vec2::new(-1.0, 0.0),
+            }),
+            contact(&trans_a, &img_a, &trans_b, &img_b)
+        );
+
+        // Seen from the other side the normal flips
+        let contact_b = contact(&trans_b, &img_b, &trans_a, &img_a).unwrap();
+        assert_eq!(Vec2::new(1.0, 0.0), contact_b.normal);
+        assert_eq!(Vec2::new(1.5, -1.5), contact_b.point);
+
+        assert_eq!(
+            None,
+            contact(
+                &Transform::from_xyz(-1.0, 0.0, 0.0),
+                &img_a,
+                &trans_b,
+                &img_b
+            )
+        );
+    }
+
+    #[test]
+    fn contact_only_covers_opaque_pixels() {
+        // Only the middle 2x2 pixels of the o image are opaque
+        let img_a = CollisionMask::from_image(&generate_image_o());
+        let img_b = CollisionMask::from_image(&generate_image());
+        let trans_a = Transform::from_xyz(0.0, 2.5, 0.0);
+        let trans_b = Transform::from_xyz(0.0, 0.0, 0.0);
+
+        let contact = contact(&trans_a, &img_a, &trans_b, &img_b).unwrap();
+        assert_eq!(Vec2::new(-1.0, 1.5), contact.overlap_min);
+        assert_eq!(Vec2::new(1.0, 2.0), contact.overlap_max);
+        assert_eq!(Vec2::new(-0.5, 1.75), contact.point);
+        assert_eq!(Vec2::new(0.0, 1.0), contact.normal);
+    }
+
+    #[test]
+    fn contact_rotated() {
+        let img_a = CollisionMask::from_image(&generate_image());
+        let img_b = CollisionMask::from_image(&generate_image());
+        let mut trans_a = Transform::from_xyz(0.0, 0.0, 0.0);
+        trans_a.rotate_z(f32::to_radians(45.0));
+        let trans_b = Transform::from_xyz(-3.0, -3.0, 0.0);
+
+        // The tip of the diamond pokes into the corner of b
+        let contact = contact(&trans_a, &img_a, &trans_b, &img_b).unwrap();
+        assert!(contact.overlap_max.x <= -1.0 && contact.overlap_max.y <= -1.0);
+        assert!(contact.normal.x > 0.0 && contact.normal.y > 0.0);
+    }
+
+    #[test]
+    fn contact_matches_collide() {
+        let img_a = CollisionMask::from_image(&generate_image_l());
+        let img_b = CollisionMask::from_image(&generate_image_o());
+        let trans_b = Transform::from_xyz(0.0, 0.0, 0.0);
+        for x in -5..5 {
+            for y in -5..5 {
+                let mut trans_a = Transform::from_xyz(x as f32 * 0.7, y as f32 * 0.9, 0.0);
+                trans_a.rotate_z(x as f32 * 0.3);
+                assert_eq!(
+                    collide(&trans_a, &img_a, &trans_b, &img_b),
+                    contact(&trans_a, &img_a, &trans_b, &img_b).is_some()
+                );
+            }
+        }
+    }
 }
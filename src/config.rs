@@ -3,18 +3,65 @@ use bevy::prelude::*;
 pub const TIME_STEP: f32 = 1.0 / 60.0;
 pub const MAP_BOUNDS: Vec2 = Vec2::new(1024.0, 1024.0);
 pub const WINDOW_BOUNDS: Vec2 = Vec2::new(640.0, 1024.0);
-pub const ENEMY_MOVEMENT_SEED: f32 = 100.0;
 pub const SHOT_MOVEMENT_SEED: f32 = 800.0;
 pub const SHOT_SPEED: f32 = 0.8;
+pub const SHOT_DAMAGE: i32 = 1;
+// Tint of a sprite that just took damage and for how many seconds
+pub const HIT_FLASH_COLOR: Color = Color::rgb(1.0, 0.3, 0.3);
+pub const HIT_FLASH_TIME: f32 = 0.1;
 
 pub const SCROLL_SPEED: f32 = 150.0;
 pub const TILE_SIDE: f32 = 32.0;
 pub const TILES_PER_WIDTH: i32 = (MAP_BOUNDS.x / TILE_SIDE) as i32;
 pub const ROWS_PER_HEIGHT: i32 = (MAP_BOUNDS.y / TILE_SIDE) as i32;
+// Free passage through generated rows in tiles, it has to fit a ship three tiles wide
+// even when it drifts in every row
+pub const CORRIDOR_WIDTH: i32 = 6;
+pub const CORRIDOR_MAX_DRIFT: i32 = 1;
+
+// Seconds between the enemies of a spawn group
+pub const WAVE_SPAWN_INTERVAL: f32 = 0.5;
+// Speed on top of the leader one formation followers get back to their place with
+pub const FORMATION_CATCH_UP_SPEED: f32 = 100.0;
+
+// Enemy behaviors. Sine enemies sway this far to the sides this many times a second, homing
+// ones turn at most this many radians a second, dive bombers dive once the player is in
+// range, orbiting ones circle at the radius and chargers hover at HOVER_Y before charging.
+pub const SINE_AMPLITUDE: f32 = 3.0 * TILE_SIDE;
+pub const SINE_FREQUENCY: f32 = 0.5;
+pub const HOMING_TURN_RATE: f32 = 1.5;
+pub const DIVE_RANGE: f32 = 400.0;
+pub const DIVE_SPEED: f32 = 450.0;
+pub const ORBIT_RADIUS: f32 = 3.0 * TILE_SIDE;
+pub const ORBIT_ANGULAR_SPEED: f32 = 2.0;
+pub const HOVER_Y: f32 = MAP_BOUNDS.y / 4.0;
+pub const HOVER_TIME: f32 = 1.5;
+pub const CHARGE_SPEED: f32 = 500.0;
+
+// Bosses fly in at this speed until their middle gets down to BOSS_Y
+pub const BOSS_ENTRY_SPEED: f32 = 100.0;
+pub const BOSS_Y: f32 = MAP_BOUNDS.y / 2.0 - 4.0 * TILE_SIDE;
+
+// Background layers from the farthest one, texture and speed relative to the map scroll
+// speed. Textures have to wrap around vertically.
+pub const BACKGROUND_LAYERS: [(&str, f32); 3] = [
+    ("textures/background/starfield.png", 0.1),
+    ("textures/background/nebula.png", 0.25),
+    ("textures/background/rocks.png", 0.5),
+];
+// Depth of the farthest layer, the others are one closer each
+pub const BACKGROUND_Z: f32 = -10.0;
 
 // Player
 pub const PLAYER_SPEED: f32 = 500.0;
 pub const PLAYER_HEALTH: i32 = 3;
+// Seconds on a hazardous tile before it takes a health
+pub const HAZARD_DAMAGE_INTERVAL: f32 = 0.5;
+// Health taken by a hazard tick or by crashing into an enemy
+pub const HAZARD_DAMAGE: i32 = 1;
+pub const CRASH_DAMAGE: i32 = 1;
+// Seconds touching a boss before it takes another crash damage
+pub const BOSS_CRASH_INTERVAL: f32 = 0.5;
 
 // Scoreboard
 pub const SCOREBOARD_FONT_SIZE: f32 = 40.0;
@@ -24,3 +71,27 @@ pub const SCOREBOARD_TEXT_PADDING: Val = Val::Px(5.0);
 
 // Health
 pub const HEALTH_TEXT_PADDING_TOP: Val = Val::Px(45.0);
+pub const BOSS_BAR_PADDING_TOP: Val = Val::Px(15.0);
+pub const BOSS_BAR_HEIGHT: Val = Val::Px(16.0);
+pub const BOSS_BAR_COLOR: Color = Color::rgb(0.8, 0.1, 0.2);
+pub const BOSS_BAR_BACKGROUND_COLOR: Color = Color::rgb(0.2, 0.2, 0.25);
+
+// Debug
+// Shows and hides the collision contacts, --debug-contacts shows them from the start
+pub const DEBUG_CONTACTS_KEY: KeyCode = KeyCode::F2;
+
+// Value following the name option on the command line, none when it's missing
+pub fn arg(name: &str) -> Option<String> {
+    let mut args = std::env::args();
+    args.position(|arg| arg == name)?;
+    let value = args.next();
+    if value.is_none() {
+        warn!("{} needs a value, ignoring it", name);
+    }
+    value
+}
+
+// Whether the name option is on the command line
+pub fn flag(name: &str) -> bool {
+    std::env::args().any(|arg| arg == name)
+}
@@ -1,5 +1,7 @@
+use crate::collision;
 use crate::config;
 use bevy::prelude::*;
+use bevy_prototype_debug_lines::*;
 
 pub struct DebugPlugin;
 
@@ -8,14 +10,30 @@ pub struct DebugCollideDraw;
 
 impl Plugin for DebugPlugin {
     fn build(&self, app: &mut App) {
+        app.init_resource::<DebugOverlays>();
         app.add_system(dragging_system);
         app.add_system(clear_debug_draws);
+        app.add_system(toggle_overlays_system);
+        app.add_system(draw_collision_contacts.after(collision::CollisionDetection));
     }
 }
 
 #[derive(Component)]
 pub struct Draggable;
 
+// Debug overlays that are shown, their keys in config toggle them
+pub struct DebugOverlays {
+    pub contacts: bool,
+}
+
+impl Default for DebugOverlays {
+    fn default() -> DebugOverlays {
+        DebugOverlays {
+            contacts: config::flag("--debug-contacts"),
+        }
+    }
+}
+
 #[allow(dead_code)]
 pub fn spawn_square(commands: &mut Commands, p1: Vec3, p2: Vec3, color: Color) {
     let mut scale = p1 - p2;
@@ -79,3 +97,45 @@ fn clear_debug_draws(mut commands: Commands, mut query: Query<Entity, With<Debug
         commands.entity(e).despawn();
     }
 }
+
+fn toggle_overlays_system(
+    keyboard_input: Res<Input<KeyCode>>,
+    mut overlays: ResMut<DebugOverlays>,
+) {
+    if keyboard_input.just_pressed(config::DEBUG_CONTACTS_KEY) {
+        overlays.contacts = !overlays.contacts;
+    }
+}
+
+// Draws a cross at the contact point and a line along the contact normal
+fn draw_collision_contacts(
+    overlays: Res<DebugOverlays>,
+    mut lines: ResMut<DebugLines>,
+    mut collision_events: EventReader<collision::CollisionEvent>,
+) {
+    if !overlays.contacts {
+        collision_events.clear();
+        return;
+    }
+    for event in collision_events.iter() {
+        let point = event.contact.point.extend(0.0);
+        lines.line_colored(
+            point + Vec3::new(-3.0, -3.0, 0.0),
+            point + Vec3::new(3.0, 3.0, 0.0),
+            0.5,
+            Color::YELLOW,
+        );
+        lines.line_colored(
+            point + Vec3::new(-3.0, 3.0, 0.0),
+            point + Vec3::new(3.0, -3.0, 0.0),
+            0.5,
+            Color::YELLOW,
+        );
+        lines.line_colored(
+            point,
+            point + event.contact.normal.extend(0.0) * 15.0,
+            0.5,
+            Color::ORANGE,
+        );
+    }
+}
@@ -1,48 +1,84 @@
 use bevy::prelude::*;
 
+use crate::animation::{Animation, AnimationFinished};
+use crate::archetypes::EnemyArchetype;
+use crate::behaviors::{Behavior, Steering};
+use crate::collision;
 use crate::config;
+use crate::formations::Follower;
+use crate::health::{self, DeathEvent, Health, HitFlash};
 use crate::map;
+use crate::player;
+use crate::spritesheet;
+use crate::ui;
+use crate::weapons::Weapon;
 use bevy_prototype_debug_lines::*;
-use rand::Rng;
 
 pub struct EnemiesPlugin;
 
-struct SpawnEnemiesTimer(Timer);
-
 #[derive(Component)]
 pub struct Enemy {
     //TODO(amatej): not sure if _alive is wanted -> I delete it when killed..
     _alive: bool,
     pub path: Vec<map::Pos>,
     pub scroll_offset: Vec3,
+    // Added to the score when the enemy is killed
+    pub score: usize,
+    // Side of the square of tiles the enemy takes
+    pub size: i32,
 }
 
 #[derive(Component)]
 pub struct Advancing {
-    movement_speed: f32,
+    pub movement_speed: f32,
 }
 
 impl Plugin for EnemiesPlugin {
     fn build(&self, app: &mut App) {
-        app.insert_resource(SpawnEnemiesTimer(Timer::from_seconds(0.5, true)))
-            .add_system(advancing_enemies_system)
-            .add_system(spawn_enemies_system)
-            .add_system(despawn_enemies_system);
+        app.add_system(advancing_enemies_system)
+            .add_system(despawn_enemies_system)
+            .add_system(enemy_death_system.after(health::DamageApplication))
+            .add_system(despawn_dead_enemies_system);
     }
 }
 
+type AdvancingQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Advancing,
+        &'static mut Behavior,
+        &'static mut Transform,
+        &'static mut Enemy,
+    ),
+    Without<Follower>,
+>;
+
 fn advancing_enemies_system(
     map: Res<map::Map>,
     mut lines: ResMut<DebugLines>,
-    mut query: Query<(&Advancing, &mut Transform, &mut Enemy)>,
+    player_query: Query<&Transform, (With<player::Player>, Without<Enemy>)>,
+    // Followers of formations go after their leader
+    mut query: AdvancingQuery,
 ) {
-    for (advacing, mut trans, mut enemy) in &mut query {
-        let advancing_direction: Vec3;
-        if let Some(t) = enemy.path.first() {
-            let target = t.to_world_vec3() - enemy.scroll_offset;
-
-            advancing_direction = (target - trans.translation).normalize();
-
+    // Enemies head for the bottom of the map once the player is gone
+    let player = player_query
+        .get_single()
+        .map(|player| player.translation)
+        .unwrap_or(Vec3::new(0.0, -config::MAP_BOUNDS.y / 2.0, 0.0));
+    for (advacing, mut behavior, mut trans, mut enemy) in &mut query {
+        let waypoint = enemy
+            .path
+            .first()
+            .map(|t| t.to_world_vec3() - enemy.scroll_offset);
+        let steering = Steering {
+            player,
+            waypoint,
+            speed: advacing.movement_speed,
+            scroll_speed: map.scroll_speed,
+        };
+        trans.translation = behavior.step(trans.translation, &steering, config::TIME_STEP);
+        if let Some(target) = waypoint {
             // Debug draw path
             let mut previous_i = trans.translation;
             for i in &enemy.path {
@@ -58,73 +94,61 @@ fn advancing_enemies_system(
             {
                 enemy.path.remove(0);
             }
-            let advacing_distance: f32 =
-                (advacing.movement_speed + map.scroll_speed) * config::TIME_STEP;
-            let advacing_delta = advancing_direction * advacing_distance;
-            trans.translation += advacing_delta;
-        } else {
-            // Stand on the current spot -> there is no way to pass
-            // This could be important especially for bigger sizes of ships (enemies)
         }
     }
 }
 
-fn spawn_enemies_system(
-    time: Res<Time>,
-    mut timer: ResMut<SpawnEnemiesTimer>,
-    mut commands: Commands,
-    mut tile_query: Query<&Transform, With<map::Tile>>,
-    asset_server: Res<AssetServer>,
-) {
-    if timer.0.tick(time.delta()).just_finished() {
-        //TODO(amatej): I think the texture should be a resource? - load it just once
-        let enemy_handle = asset_server.load("textures/enemy_A.png");
-        let random_pos = rand::thread_rng().gen_range(
-            ((-(config::TILES_PER_WIDTH - 3) as f32 / 2.0) as i32)
-                ..(((config::TILES_PER_WIDTH - 3) as f32 / 2.0) as i32),
-        );
-        let random_pos_world = random_pos as f32 * config::TILE_SIDE;
-
-        let mut random_pos_clear = true;
-        // check if picked random_pos is free and don't spawn enemy if it isn't
-        for tile_trans in &mut tile_query {
-            if tile_trans.translation.y >= config::MAP_BOUNDS.y / 2.0 - 2.0 * config::TILE_SIDE {
-                if tile_trans.translation.x >= random_pos_world - 2.0 * config::TILE_SIDE
-                    && tile_trans.translation.x <= random_pos_world + 2.0 * config::TILE_SIDE
-                {
-                    random_pos_clear = false;
-                    break;
-                }
-            }
-        }
+// Whether an enemy of size fits in at the top of the map at world x with a free tile on its
+// sides
+pub fn spawn_clear(map: &map::Map, x: f32, size: i32) -> bool {
+    let spawn_pos = map::Pos::from_world_vec3(&Vec3::new(x, config::MAP_BOUNDS.y / 2.0, 0.0));
+    let half = size / 2;
+    map.grid
+        .cells(
+            &spawn_pos
+                + &map::Pos {
+                    x: -half - 1,
+                    y: 1 - size,
+                },
+            &spawn_pos + &map::Pos { x: half + 1, y: 0 },
+        )
+        .all(|(_, free)| free)
+}
 
-        if random_pos_clear {
-            let random_speed_offset =
-                rand::thread_rng().gen_range(0.0..config::ENEMY_MOVEMENT_SEED);
-            let mut enemy_start_transform =
-                Transform::from_xyz(random_pos_world, config::MAP_BOUNDS.y / 2.0, 0.0);
-            enemy_start_transform.rotate_z(f32::to_radians(180.0));
-            commands
-                .spawn_bundle(SpriteBundle {
-                    texture: enemy_handle,
-                    transform: enemy_start_transform,
-                    ..default()
-                })
-                .insert(Advancing {
-                    movement_speed: random_speed_offset,
-                })
-                .insert(Enemy {
-                    _alive: true,
-                    scroll_offset: Vec3::ZERO,
-                    path: vec![
-                        //map::Pos{0:0, 1:0},
-                        //Vec2::new(random_pos_world, (config::MAP_BOUNDS.y / 2.0) - 430.0),
-                        //Vec2::new(random_pos_world-40.0, (config::MAP_BOUNDS.y / 2.0) - 240.0),
-                        //Vec2::new(random_pos_world+40.0, (config::MAP_BOUNDS.y / 2.0) - 130.0),
-                    ],
-                });
-        }
+// Spawns an enemy of the archetype heading down from translation, through the waypoints of
+// path first if there are any
+pub fn spawn_enemy(
+    commands: &mut Commands,
+    sprites: &spritesheet::SheetSprites,
+    archetype: &EnemyArchetype,
+    translation: Vec3,
+    movement_speed: f32,
+    path: Vec<map::Pos>,
+) -> Entity {
+    let mut enemy_start_transform = Transform::from_translation(translation);
+    enemy_start_transform.rotate_z(f32::to_radians(180.0));
+    let mut enemy = sprites.spawn(commands, &archetype.sprite, enemy_start_transform);
+    if let Some(weapon) = &archetype.weapon {
+        enemy.insert(Weapon::new(weapon));
     }
+    enemy
+        .insert(Advancing { movement_speed })
+        .insert(Animation::new(&archetype.sprite, "idle"))
+        .insert(Health::new(archetype.health))
+        .insert(HitFlash::default())
+        .insert(collision::Collider {
+            layer: collision::LAYER_ENEMY,
+            mask: collision::LAYER_PLAYER | collision::LAYER_SHOT,
+        })
+        .insert(Enemy {
+            _alive: true,
+            scroll_offset: Vec3::ZERO,
+            path,
+            score: archetype.score,
+            size: archetype.size,
+        })
+        .insert(Behavior::new(archetype.movement, translation))
+        .id()
 }
 
 // Despawns enemies that go outside of the screen
@@ -140,3 +164,35 @@ fn despawn_enemies_system(
         }
     }
 }
+
+// Scores enemies out of health, stops them and plays their death clip, they despawn when
+// the clip finishes
+fn enemy_death_system(
+    mut commands: Commands,
+    mut scoreboard: ResMut<ui::Scoreboard>,
+    mut death_events: EventReader<DeathEvent>,
+    mut query: Query<(&Enemy, &mut Animation)>,
+) {
+    for death in death_events.iter() {
+        if let Ok((enemy, mut animation)) = query.get_mut(death.entity) {
+            scoreboard.score += enemy.score;
+            commands
+                .entity(death.entity)
+                .remove::<Advancing>()
+                .remove::<collision::Collider>();
+            animation.play("death");
+        }
+    }
+}
+
+fn despawn_dead_enemies_system(
+    mut commands: Commands,
+    mut finished_events: EventReader<AnimationFinished>,
+    query: Query<Entity, With<Enemy>>,
+) {
+    for finished in finished_events.iter() {
+        if finished.clip == "death" && query.get(finished.entity).is_ok() {
+            commands.entity(finished.entity).despawn();
+        }
+    }
+}
@@ -0,0 +1,267 @@
+use bevy::prelude::*;
+use std::f32::consts::{PI, TAU};
+
+use crate::archetypes::EnemyArchetype;
+use crate::config;
+use crate::enemies;
+use crate::health::{self, DeathEvent};
+use crate::map;
+use crate::spritesheet::SheetSprites;
+use crate::ui;
+
+// Enemies flying together. The leader finds its way like any other enemy, followers keep
+// their place around it and fall in behind it while their place is blocked. When the
+// leader dies the first follower left takes over.
+pub struct FormationsPlugin;
+
+impl Plugin for FormationsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(follow_leader_system)
+            .add_system(formation_system.after(health::DamageApplication));
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Formation {
+    // Every enemy on its own
+    None,
+    V,
+    Line,
+    Column,
+    Circle,
+}
+
+// Enemy keeping its place around the leader of its formation
+#[derive(Component)]
+pub struct Follower {
+    leader: Entity,
+    offset: Vec2,
+}
+
+// Members of a formation, in the order of the offsets they got at spawn
+#[derive(Component)]
+struct FormationGroup {
+    members: Vec<Entity>,
+    offsets: Vec<Vec2>,
+    leader: usize,
+    // Members killed by the player
+    killed: usize,
+    // Score for killing all of them
+    bonus: usize,
+}
+
+// Places of count enemies relative to the leader, which is the first one at zero. Enemies
+// fly down so the places behind the leader are above it.
+pub fn offsets(formation: Formation, count: u32, spacing: f32) -> Vec<Vec2> {
+    (0..count)
+        .map(|index| {
+            // Alternately to the right and left of the leader, further every other enemy
+            let side = if index % 2 == 1 { 1.0 } else { -1.0 };
+            let rank = index.div_ceil(2) as f32;
+            match formation {
+                Formation::None => Vec2::ZERO,
+                Formation::V => Vec2::new(side * rank, rank) * spacing,
+                Formation::Line => Vec2::new(side * rank * spacing, 0.0),
+                Formation::Column => Vec2::new(0.0, index as f32 * spacing),
+                Formation::Circle if index == 0 => Vec2::ZERO,
+                Formation::Circle => {
+                    // Around the leader, far enough for all of them to fit on the circle
+                    let around = count - 1;
+                    let radius = match around {
+                        1 => spacing,
+                        _ => spacing.max(spacing / (2.0 * (PI / around as f32).sin())),
+                    };
+                    let angle = TAU * (index - 1) as f32 / around as f32;
+                    Vec2::new(angle.sin(), angle.cos()) * radius
+                }
+            }
+        })
+        .collect()
+}
+
+// Where a follower heads with the leader at leader, its place when free, the spot behind
+// the leader at the same distance otherwise and the leader itself when even that is taken
+pub fn follower_target(leader: Vec3, offset: Vec2, free: impl Fn(Vec3) -> bool) -> Vec3 {
+    let place = leader + offset.extend(0.0);
+    if free(place) {
+        return place;
+    }
+    let behind = leader + Vec3::new(0.0, offset.length(), 0.0);
+    if free(behind) {
+        return behind;
+    }
+    leader
+}
+
+// Spawns count enemies of the archetype with the leader at translation, the leader flies
+// through the waypoints of path first
+#[allow(clippy::too_many_arguments)]
+pub fn spawn_formation(
+    commands: &mut Commands,
+    sprites: &SheetSprites,
+    formation: Formation,
+    count: u32,
+    archetype: &EnemyArchetype,
+    translation: Vec3,
+    movement_speed: f32,
+    mut path: Vec<map::Pos>,
+) {
+    let spacing = archetype.size as f32 * config::TILE_SIDE;
+    let offsets = offsets(formation, count, spacing);
+    let mut members: Vec<Entity> = Vec::new();
+    for offset in &offsets {
+        let member = enemies::spawn_enemy(
+            commands,
+            sprites,
+            archetype,
+            translation + offset.extend(0.0),
+            movement_speed,
+            // Followers keep to the leader
+            std::mem::take(&mut path),
+        );
+        if let Some(&leader) = members.first() {
+            commands.entity(member).insert(Follower {
+                leader,
+                offset: *offset,
+            });
+        }
+        members.push(member);
+    }
+    commands.spawn().insert(FormationGroup {
+        members,
+        offsets,
+        leader: 0,
+        killed: 0,
+        bonus: archetype.score * count as usize,
+    });
+}
+
+fn follow_leader_system(
+    map: Res<map::Map>,
+    leader_query: Query<&Transform, Without<Follower>>,
+    mut follower_query: Query<(
+        &Follower,
+        &enemies::Advancing,
+        &enemies::Enemy,
+        &mut Transform,
+    )>,
+) {
+    for (follower, advancing, enemy, mut transform) in &mut follower_query {
+        let leader = match leader_query.get(follower.leader) {
+            Ok(leader) => leader,
+            Err(_) => continue,
+        };
+        let target = follower_target(leader.translation, follower.offset, |target| {
+            let mut pos = map::Pos::from_world_vec3(&target);
+            // Formations come in from above the map, up there they need the top rows free
+            pos.y = pos.y.min(config::ROWS_PER_HEIGHT - 1 - enemy.size / 2);
+            map::fits(&map.grid, pos, enemy.size)
+        });
+        // Faster than the leader to catch up with it
+        let max_distance =
+            (advancing.movement_speed + map.scroll_speed + config::FORMATION_CATCH_UP_SPEED)
+                * config::TIME_STEP;
+        let delta = target - transform.translation;
+        transform.translation += delta.clamp_length_max(max_distance);
+    }
+}
+
+// Hands the formation over to a new leader when the current one is gone and scores the
+// bonus once all members are killed
+fn formation_system(
+    mut commands: Commands,
+    mut scoreboard: ResMut<ui::Scoreboard>,
+    mut death_events: EventReader<DeathEvent>,
+    mut group_query: Query<(Entity, &mut FormationGroup)>,
+    alive_query: Query<(), With<enemies::Advancing>>,
+    mut follower_query: Query<&mut Follower>,
+) {
+    let deaths: Vec<Entity> = death_events.iter().map(|death| death.entity).collect();
+    for (group_entity, mut group) in &mut group_query {
+        let killed = deaths
+            .iter()
+            .filter(|entity| group.members.contains(entity))
+            .count();
+        group.killed += killed;
+        let alive: Vec<usize> = (0..group.members.len())
+            .filter(|index| alive_query.get(group.members[*index]).is_ok())
+            .collect();
+        let new_leader = match alive.first() {
+            Some(new_leader) => *new_leader,
+            None => {
+                // Members that flew away or crashed spoil the bonus
+                if group.killed == group.members.len() {
+                    scoreboard.score += group.bonus;
+                }
+                commands.entity(group_entity).despawn();
+                continue;
+            }
+        };
+        if alive.contains(&group.leader) {
+            continue;
+        }
+        group.leader = new_leader;
+        let leader = group.members[new_leader];
+        commands.entity(leader).remove::<Follower>();
+        for index in &alive[1..] {
+            if let Ok(mut follower) = follower_query.get_mut(group.members[*index]) {
+                follower.leader = leader;
+                follower.offset = group.offsets[*index] - group.offsets[new_leader];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_of_formations() {
+        assert_eq!(
+            vec![Vec2::ZERO, Vec2::new(1.0, 1.0), Vec2::new(-1.0, 1.0)],
+            offsets(Formation::V, 3, 1.0)
+        );
+        assert_eq!(
+            vec![
+                Vec2::ZERO,
+                Vec2::new(2.0, 0.0),
+                Vec2::new(-2.0, 0.0),
+                Vec2::new(4.0, 0.0)
+            ],
+            offsets(Formation::Line, 4, 2.0)
+        );
+        assert_eq!(
+            vec![Vec2::ZERO, Vec2::new(0.0, 3.0), Vec2::new(0.0, 6.0)],
+            offsets(Formation::Column, 3, 3.0)
+        );
+        assert_eq!(vec![Vec2::ZERO; 2], offsets(Formation::None, 2, 3.0));
+    }
+
+    #[test]
+    fn circle_fits_all_followers() {
+        let circle = offsets(Formation::Circle, 13, 10.0);
+        assert_eq!(Vec2::ZERO, circle[0]);
+        for (index, offset) in circle.iter().enumerate().skip(1) {
+            assert!(offset.length() >= 10.0);
+            let next = circle[index % 12 + 1];
+            assert!(offset.distance(next) >= 9.99, "{} and {}", offset, next);
+        }
+    }
+
+    #[test]
+    fn follower_falls_in_behind_blocked_place() {
+        let leader = Vec3::new(10.0, 0.0, 0.0);
+        let offset = Vec2::new(3.0, 4.0);
+        assert_eq!(
+            Vec3::new(13.0, 4.0, 0.0),
+            follower_target(leader, offset, |_| true)
+        );
+        // Wall on the right
+        assert_eq!(
+            Vec3::new(10.0, 5.0, 0.0),
+            follower_target(leader, offset, |target| target.x < 12.0)
+        );
+        assert_eq!(leader, follower_target(leader, offset, |_| false));
+    }
+}
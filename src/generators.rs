@@ -0,0 +1,402 @@
+use crate::config;
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng};
+use std::ops::Range;
+
+// Cells are tile columns of a row, zero is the middle of the map. Middle tiles go from the
+// left wall cell to the last cell before the right wall.
+pub const FIRST_CELL: i32 = -config::TILES_PER_WIDTH / 2;
+pub const LAST_CELL: i32 = config::TILES_PER_WIDTH / 2 - 1;
+
+// Decides where the tiles of each new row go, the side walls are always added by the map
+pub trait RowGenerator: Send + Sync {
+    // Cells of the middle tiles in the row with index (counting from the first generated
+    // row). Previous are the rows generated before, the last one is right under the new
+    // row. All randomness has to come from rng so the same seed gives the same map.
+    fn generate(&mut self, index: u32, rng: &mut StdRng, previous: &[Vec<i32>]) -> Vec<i32>;
+}
+
+// Free passage of config::CORRIDOR_WIDTH cells drifting from row to row, every generator
+// keeps it free so there is always a way through
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Corridor {
+    // Leftmost free cell in the last generated row
+    pub left: i32,
+}
+
+impl Default for Corridor {
+    fn default() -> Corridor {
+        Corridor {
+            left: -config::CORRIDOR_WIDTH / 2,
+        }
+    }
+}
+
+impl Corridor {
+    pub fn cells(&self) -> Range<i32> {
+        self.left..self.left + config::CORRIDOR_WIDTH
+    }
+
+    // Drifts into the next row
+    pub fn advance(&mut self, rng: &mut StdRng) -> Range<i32> {
+        let drift = rng.gen_range(-config::CORRIDOR_MAX_DRIFT..=config::CORRIDOR_MAX_DRIFT);
+        // Keep the corridor off the side walls
+        self.left =
+            (self.left + drift).clamp(FIRST_CELL + 1, LAST_CELL + 1 - config::CORRIDOR_WIDTH);
+        self.cells()
+    }
+
+    // Removes the cells of the corridor from tiles
+    fn clear(&self, tiles: &mut Vec<i32>) {
+        let free = self.cells();
+        tiles.retain(|cell| !free.contains(cell));
+    }
+}
+
+// Scattered short walls
+#[derive(Default)]
+pub struct RandomRows {
+    pub corridor: Corridor,
+}
+
+impl RowGenerator for RandomRows {
+    fn generate(&mut self, _index: u32, rng: &mut StdRng, _previous: &[Vec<i32>]) -> Vec<i32> {
+        let free = self.corridor.advance(rng);
+
+        let mut random_change_offset = 0;
+        let mut tiles: Vec<i32> = Vec::new();
+        for cell in FIRST_CELL..=LAST_CELL {
+            if free.contains(&cell) {
+                random_change_offset = 0;
+                continue;
+            }
+            let random_chance = rng.gen_range(0..100);
+            //println!("random chance: {:?}", random_chance);
+            if random_chance > 98 - random_change_offset {
+                if random_change_offset == 0 {
+                    random_change_offset = 80;
+                } else {
+                    random_change_offset -= 20;
+                }
+
+                tiles.push(cell);
+            } else {
+                random_change_offset = 0;
+            }
+        }
+        tiles
+    }
+}
+
+// Open space with a few islands that grow and shrink from row to row
+pub struct IslandRows {
+    // Chance in percent of a new island starting in a cell
+    pub new_island_chance: u32,
+    // Chance in percent of an island going on into the next row
+    pub keep_island_chance: u32,
+    pub corridor: Corridor,
+}
+
+impl Default for IslandRows {
+    fn default() -> IslandRows {
+        IslandRows {
+            new_island_chance: 1,
+            keep_island_chance: 80,
+            corridor: Corridor::default(),
+        }
+    }
+}
+
+impl RowGenerator for IslandRows {
+    fn generate(&mut self, _index: u32, rng: &mut StdRng, previous: &[Vec<i32>]) -> Vec<i32> {
+        let mut tiles: Vec<i32> = Vec::new();
+        if let Some(last) = previous.last() {
+            for island in islands(last) {
+                if rng.gen_range(0..100) >= self.keep_island_chance {
+                    continue;
+                }
+                // Grow or shrink by a tile on each side
+                let from = (island.0 + rng.gen_range(-1..=1)).max(FIRST_CELL + 1);
+                let to = (island.1 + rng.gen_range(-1..=1)).min(LAST_CELL);
+                tiles.extend(from..=to);
+            }
+        }
+        for cell in FIRST_CELL + 1..=LAST_CELL {
+            if rng.gen_range(0..100) < self.new_island_chance {
+                tiles.push(cell);
+            }
+        }
+        tiles.sort_unstable();
+        tiles.dedup();
+        self.corridor.advance(rng);
+        self.corridor.clear(&mut tiles);
+        tiles
+    }
+}
+
+// Winding tunnel through solid rock, open for a margin on each side of the corridor that
+// widens and narrows from row to row
+pub struct CaveRows {
+    // Most free cells on one side of the corridor
+    pub max_margin: i32,
+    pub corridor: Corridor,
+    // Free cells left and right of the corridor in the last row
+    margins: (i32, i32),
+}
+
+impl Default for CaveRows {
+    fn default() -> CaveRows {
+        CaveRows {
+            max_margin: 6,
+            corridor: Corridor::default(),
+            margins: (3, 3),
+        }
+    }
+}
+
+impl RowGenerator for CaveRows {
+    fn generate(&mut self, _index: u32, rng: &mut StdRng, _previous: &[Vec<i32>]) -> Vec<i32> {
+        let free = self.corridor.advance(rng);
+        let mut margin = |margin: i32| (margin + rng.gen_range(-1..=1)).clamp(0, self.max_margin);
+        self.margins = (margin(self.margins.0), margin(self.margins.1));
+        let open = free.start - self.margins.0..free.end + self.margins.1;
+        (FIRST_CELL + 1..=LAST_CELL)
+            .filter(|cell| !open.contains(cell))
+            .collect()
+    }
+}
+
+// Small rocks scattered over open space, some of them a few rows long
+pub struct AsteroidRows {
+    // Chance in percent of a new rock in a cell
+    pub new_rock_chance: u32,
+    // Chance in percent of a rock going on into the next row
+    pub keep_rock_chance: u32,
+    pub corridor: Corridor,
+}
+
+impl Default for AsteroidRows {
+    fn default() -> AsteroidRows {
+        AsteroidRows {
+            new_rock_chance: 3,
+            keep_rock_chance: 50,
+            corridor: Corridor::default(),
+        }
+    }
+}
+
+impl RowGenerator for AsteroidRows {
+    fn generate(&mut self, _index: u32, rng: &mut StdRng, previous: &[Vec<i32>]) -> Vec<i32> {
+        let mut tiles: Vec<i32> = Vec::new();
+        if let Some(last) = previous.last() {
+            tiles.extend(
+                last.iter()
+                    .filter(|_| rng.gen_range(0..100) < self.keep_rock_chance),
+            );
+        }
+        for cell in FIRST_CELL + 1..=LAST_CELL {
+            if rng.gen_range(0..100) < self.new_rock_chance {
+                // One or two tiles wide
+                tiles.push(cell);
+                if rng.gen_bool(0.5) && cell < LAST_CELL {
+                    tiles.push(cell + 1);
+                }
+            }
+        }
+        tiles.sort_unstable();
+        tiles.dedup();
+        self.corridor.advance(rng);
+        self.corridor.clear(&mut tiles);
+        tiles
+    }
+}
+
+// Narrow gorge with walls right next to the corridor
+pub struct CanyonRows {
+    // Free cells on each side of the corridor
+    pub margin: i32,
+    pub corridor: Corridor,
+}
+
+impl Default for CanyonRows {
+    fn default() -> CanyonRows {
+        CanyonRows {
+            margin: 1,
+            corridor: Corridor::default(),
+        }
+    }
+}
+
+impl RowGenerator for CanyonRows {
+    fn generate(&mut self, _index: u32, rng: &mut StdRng, _previous: &[Vec<i32>]) -> Vec<i32> {
+        let free = self.corridor.advance(rng);
+        let open = free.start - self.margin..free.end + self.margin;
+        (FIRST_CELL + 1..=LAST_CELL)
+            .filter(|cell| !open.contains(cell))
+            .collect()
+    }
+}
+
+// Names of the generators for `--generator <name>`
+pub const GENERATORS: [&str; 5] = ["random", "islands", "caves", "asteroids", "canyons"];
+
+// Makes the generator with the name
+pub fn by_name(name: &str) -> Option<fn() -> Box<dyn RowGenerator>> {
+    match name {
+        "random" => Some(|| Box::new(RandomRows::default())),
+        "islands" => Some(|| Box::new(IslandRows::default())),
+        "caves" => Some(|| Box::new(CaveRows::default())),
+        "asteroids" => Some(|| Box::new(AsteroidRows::default())),
+        "canyons" => Some(|| Box::new(CanyonRows::default())),
+        _ => None,
+    }
+}
+
+// Generator picked by `--generator <name>` on the command line, random rows otherwise
+pub fn from_args() -> fn() -> Box<dyn RowGenerator> {
+    let name = config::arg("--generator").unwrap_or_else(|| "random".to_string());
+    by_name(&name).unwrap_or_else(|| {
+        error!(
+            "Unknown generator {}, use one of {}, generating random rows",
+            name,
+            GENERATORS.join(", ")
+        );
+        || Box::new(RandomRows::default())
+    })
+}
+
+// Runs of neighbouring cells (first, last) in sorted cells
+fn islands(cells: &[i32]) -> Vec<(i32, i32)> {
+    let mut runs: Vec<(i32, i32)> = Vec::new();
+    for cell in cells {
+        match runs.last_mut() {
+            Some(run) if run.1 + 1 == *cell => run.1 = *cell,
+            _ => runs.push((*cell, *cell)),
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    fn generate_rows(generator: &mut dyn RowGenerator, seed: u64, count: u32) -> Vec<Vec<i32>> {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut rows: Vec<Vec<i32>> = Vec::new();
+        for index in 0..count {
+            let row = generator.generate(index, &mut rng, &rows);
+            rows.push(row);
+        }
+        rows
+    }
+
+    #[test]
+    fn random_rows_keep_corridor_in_bounds() {
+        let mut generator = RandomRows::default();
+        let mut rng = StdRng::seed_from_u64(1);
+        for index in 0..10000 {
+            let previous = generator.corridor.left;
+            let cells = generator.generate(index, &mut rng, &[]);
+            let corridor = generator.corridor.left;
+            assert!((corridor - previous).abs() <= config::CORRIDOR_MAX_DRIFT);
+            assert!(corridor > FIRST_CELL);
+            assert!(corridor + config::CORRIDOR_WIDTH <= LAST_CELL + 1);
+            for cell in cells {
+                assert!(!(corridor..corridor + config::CORRIDOR_WIDTH).contains(&cell));
+            }
+        }
+    }
+
+    #[test]
+    fn generators_stay_inside_walls() {
+        for name in GENERATORS {
+            let mut generator = by_name(name).unwrap()();
+            for row in generate_rows(generator.as_mut(), 3, 500) {
+                assert!(row.iter().all(|c| (FIRST_CELL..=LAST_CELL).contains(c)));
+            }
+        }
+    }
+
+    fn assert_corridor_free<G: RowGenerator>(mut generator: G, corridor: fn(&G) -> Corridor) {
+        let mut rng = StdRng::seed_from_u64(3);
+        let mut rows: Vec<Vec<i32>> = Vec::new();
+        for index in 0..500 {
+            let row = generator.generate(index, &mut rng, &rows);
+            let free = corridor(&generator).cells();
+            assert!(row.iter().all(|c| !free.contains(c)));
+            rows.push(row);
+        }
+    }
+
+    #[test]
+    fn generators_keep_corridor_free() {
+        assert_corridor_free(RandomRows::default(), |g| g.corridor);
+        assert_corridor_free(IslandRows::default(), |g| g.corridor);
+        assert_corridor_free(CaveRows::default(), |g| g.corridor);
+        assert_corridor_free(AsteroidRows::default(), |g| g.corridor);
+        assert_corridor_free(CanyonRows::default(), |g| g.corridor);
+    }
+
+    #[test]
+    fn cave_and_canyon_rows_are_walls_around_the_open_space() {
+        let mut rng = StdRng::seed_from_u64(7);
+        let mut canyon = CanyonRows::default();
+        let row = canyon.generate(0, &mut rng, &[]);
+        let corridor = canyon.corridor.cells();
+        // Everything but the corridor and a cell on its sides
+        assert_eq!(
+            (LAST_CELL - FIRST_CELL) as usize - config::CORRIDOR_WIDTH as usize - 2,
+            row.len()
+        );
+        assert!(!row.contains(&(corridor.start - 1)));
+        assert!(row.contains(&(corridor.start - 2)));
+        assert!(row.contains(&(corridor.end + 1)));
+
+        let mut cave = CaveRows::default();
+        for index in 0..100 {
+            let row = cave.generate(index, &mut rng, &[]);
+            let open = (LAST_CELL - FIRST_CELL) as usize - row.len();
+            assert!(open >= config::CORRIDOR_WIDTH as usize);
+            assert!(open <= (config::CORRIDOR_WIDTH + 2 * cave.max_margin) as usize);
+        }
+    }
+
+    #[test]
+    fn island_rows_continue_previous_islands() {
+        let mut generator = IslandRows {
+            new_island_chance: 0,
+            keep_island_chance: 100,
+            ..IslandRows::default()
+        };
+        let mut rng = StdRng::seed_from_u64(5);
+        let previous = vec![vec![-3, -2, -1, 5, 6]];
+        let row = generator.generate(1, &mut rng, &previous);
+        assert!(!row.is_empty());
+        // Every tile is next to an island of the previous row
+        for cell in row {
+            assert!(islands(&previous[0])
+                .iter()
+                .any(|island| island.0 - 1 <= cell && cell <= island.1 + 1));
+        }
+    }
+
+    #[test]
+    fn island_rows_start_from_open_space() {
+        let mut generator = IslandRows {
+            new_island_chance: 0,
+            keep_island_chance: 100,
+            ..IslandRows::default()
+        };
+        assert!(generate_rows(&mut generator, 1, 10)
+            .iter()
+            .all(|r| r.is_empty()));
+    }
+
+    #[test]
+    fn islands_are_runs_of_cells() {
+        assert_eq!(vec![(1, 3), (5, 5), (7, 8)], islands(&[1, 2, 3, 5, 7, 8]));
+        assert!(islands(&[]).is_empty());
+    }
+}
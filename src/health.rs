@@ -0,0 +1,187 @@
+use bevy::prelude::*;
+
+use crate::config;
+
+// Health of the player and the enemies. Anything hurting them sends a DamageEvent, the
+// health is taken in one place which flashes the sprite and tells about the death.
+pub struct HealthPlugin;
+
+impl Plugin for HealthPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<DamageEvent>()
+            .add_event::<DeathEvent>()
+            .add_system(apply_damage_system.label(DamageApplication))
+            .add_system(hit_flash_system);
+    }
+}
+
+// Systems sending DamageEvents should run before this label, ones reacting to the
+// DeathEvents after it
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct DamageApplication;
+
+#[derive(Component, Debug, PartialEq)]
+pub struct Health {
+    pub current: i32,
+    pub max: i32,
+}
+
+// Health a projectile takes from what it hits
+#[derive(Component)]
+pub struct Damage(pub i32);
+
+pub struct DamageEvent {
+    pub target: Entity,
+    pub amount: i32,
+}
+
+// Sent once when the health of entity runs out
+pub struct DeathEvent {
+    pub entity: Entity,
+}
+
+// Tints the sprite until the timer finishes. Spawned together with Health on entities
+// with a sprite, hits restart the timer in place so they never add components to entities
+// that may be gone by the time commands run.
+#[derive(Component, Default)]
+pub struct HitFlash {
+    timer: Option<Timer>,
+}
+
+impl Health {
+    pub fn new(max: i32) -> Health {
+        Health { current: max, max }
+    }
+
+    pub fn is_dead(&self) -> bool {
+        self.current <= 0
+    }
+
+    // Takes amount of health, true when it just ran out
+    pub fn take(&mut self, amount: i32) -> bool {
+        if self.is_dead() {
+            return false;
+        }
+        self.current = (self.current - amount).max(0);
+        self.is_dead()
+    }
+}
+
+fn apply_damage_system(
+    mut damage_events: EventReader<DamageEvent>,
+    mut death_events: EventWriter<DeathEvent>,
+    mut query: Query<(
+        &mut Health,
+        Option<(&mut HitFlash, &mut TextureAtlasSprite)>,
+    )>,
+) {
+    for damage in damage_events.iter() {
+        if let Ok((mut health, flash)) = query.get_mut(damage.target) {
+            if health.take(damage.amount) {
+                death_events.send(DeathEvent {
+                    entity: damage.target,
+                });
+            }
+            if let Some((mut flash, mut sprite)) = flash {
+                sprite.color = config::HIT_FLASH_COLOR;
+                flash.timer = Some(Timer::from_seconds(config::HIT_FLASH_TIME, false));
+            }
+        }
+    }
+}
+
+fn hit_flash_system(time: Res<Time>, mut query: Query<(&mut HitFlash, &mut TextureAtlasSprite)>) {
+    for (mut flash, mut sprite) in &mut query {
+        let finished = match &mut flash.timer {
+            Some(timer) => timer.tick(time.delta()).finished(),
+            None => continue,
+        };
+        if finished {
+            sprite.color = Color::WHITE;
+            flash.timer = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn take_reports_death_once() {
+        let mut health = Health::new(3);
+        assert!(!health.take(1));
+        assert_eq!(2, health.current);
+        assert!(health.take(5));
+        assert_eq!(0, health.current);
+        assert!(health.is_dead());
+        assert!(!health.take(1));
+        assert_eq!(3, health.max);
+    }
+
+    #[test]
+    fn damage_events_kill_at_zero_health() {
+        let mut world = World::new();
+        let enemy = world.spawn().insert(Health::new(2)).id();
+        world.insert_resource(Events::<DamageEvent>::default());
+        world.insert_resource(Events::<DeathEvent>::default());
+        // More hits than needed in one frame still kill just once
+        for _ in 0..3 {
+            world
+                .resource_mut::<Events<DamageEvent>>()
+                .send(DamageEvent {
+                    target: enemy,
+                    amount: 1,
+                });
+        }
+
+        let mut stage = SystemStage::single(apply_damage_system);
+        stage.run(&mut world);
+
+        assert_eq!(Some(&Health { current: 0, max: 2 }), world.get(enemy));
+        let events = world.resource::<Events<DeathEvent>>();
+        let deaths: Vec<Entity> = events
+            .get_reader()
+            .iter(events)
+            .map(|death| death.entity)
+            .collect();
+        assert_eq!(vec![enemy], deaths);
+    }
+
+    #[test]
+    fn damage_to_despawned_target_is_ignored() {
+        let mut world = World::new();
+        let enemy = world
+            .spawn()
+            .insert(Health::new(2))
+            .insert(HitFlash::default())
+            .insert(TextureAtlasSprite::default())
+            .id();
+        let other = world
+            .spawn()
+            .insert(Health::new(2))
+            .insert(HitFlash::default())
+            .insert(TextureAtlasSprite::default())
+            .id();
+        world.insert_resource(Events::<DamageEvent>::default());
+        world.insert_resource(Events::<DeathEvent>::default());
+        for target in [enemy, other] {
+            world
+                .resource_mut::<Events<DamageEvent>>()
+                .send(DamageEvent { target, amount: 1 });
+        }
+        // Rammed by the player in the same frame
+        world.despawn(enemy);
+
+        let mut stage = SystemStage::single(apply_damage_system);
+        stage.run(&mut world);
+
+        assert!(world.get_entity(enemy).is_none());
+        assert_eq!(Some(&Health { current: 1, max: 2 }), world.get(other));
+        assert!(world.get::<HitFlash>(other).unwrap().timer.is_some());
+        assert_eq!(
+            config::HIT_FLASH_COLOR,
+            world.get::<TextureAtlasSprite>(other).unwrap().color
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use crate::config;
+use crate::generators;
+use crate::map::TileKind;
+use crate::parse::ParseError;
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+
+// Levels are text files drawn the way they show up on the screen, one char per tile:
+//   '#'        solid tile
+//   '%'        destructible tile
+//   '^'        hazardous tile
+//   '~'        decorative tile
+//   '.' or ' ' empty space
+//   'A'..'Z'   enemy spawn, the letter is the kind (region enemy_<kind> of the sprites sheet)
+//   '!'        end of level, this is the last row that comes in
+// The tileset picks which of its tiles of the kind shows up. The first column is the cell
+// right of the left wall, side walls are added by the map. The last line of the file comes
+// on the screen first. Lines starting with // are comments.
+pub struct LevelPlugin;
+
+impl Plugin for LevelPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Level>()
+            .init_asset_loader::<LevelLoader>()
+            .add_event::<LevelEnd>();
+    }
+}
+
+#[derive(TypeUuid)]
+#[uuid = "5d1ab3a4-6f4e-4c39-9a59-1f1c0b8e2d27"]
+pub struct Level {
+    // In the order they come on the screen
+    pub rows: Vec<LevelRow>,
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct LevelRow {
+    // Cell and kind of the middle tiles, cells like generators::RowGenerator returns
+    pub tiles: Vec<(i32, LevelTile)>,
+    // Cell and kind of enemies entering with the row
+    pub spawns: Vec<(i32, char)>,
+    pub end: bool,
+}
+
+// Kind of a tile in a level
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LevelTile {
+    Solid,
+    Destructible,
+    Hazardous,
+    Decorative,
+}
+
+impl LevelTile {
+    // Whether a tile of the tileset is of this kind
+    pub fn accepts(self, kind: &TileKind) -> bool {
+        matches!(
+            (self, kind),
+            (LevelTile::Solid, TileKind::Solid)
+                | (LevelTile::Destructible, TileKind::Destructible { .. })
+                | (LevelTile::Hazardous, TileKind::Hazardous)
+                | (LevelTile::Decorative, TileKind::Decorative)
+        )
+    }
+}
+
+// Sent when the last row of a level comes on the screen
+pub struct LevelEnd;
+
+// Cells between the side walls
+const COLUMNS: usize = (generators::LAST_CELL - generators::FIRST_CELL) as usize;
+
+pub fn parse_level(text: &str) -> Result<Level, ParseError> {
+    let mut rows: Vec<LevelRow> = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        if line.starts_with("//") {
+            continue;
+        }
+        let mut row = LevelRow::default();
+        for (column_index, c) in line.chars().enumerate() {
+            let error = |message: String| ParseError {
+                line: line_index + 1,
+                message: format!("{} in column {}", message, column_index + 1),
+            };
+            if column_index >= COLUMNS {
+                return Err(error(format!("row is wider than {} tiles", COLUMNS)));
+            }
+            let cell = generators::FIRST_CELL + 1 + column_index as i32;
+            match c {
+                '#' => row.tiles.push((cell, LevelTile::Solid)),
+                '%' => row.tiles.push((cell, LevelTile::Destructible)),
+                '^' => row.tiles.push((cell, LevelTile::Hazardous)),
+                '~' => row.tiles.push((cell, LevelTile::Decorative)),
+                '.' | ' ' => {}
+                'A'..='Z' => row.spawns.push((cell, c)),
+                '!' => row.end = true,
+                _ => return Err(error(format!("unknown tile '{}'", c))),
+            }
+        }
+        rows.push(row);
+    }
+    if rows.is_empty() {
+        return Err(ParseError {
+            line: 1,
+            message: "level has no rows".to_string(),
+        });
+    }
+    rows.reverse();
+    Ok(Level { rows })
+}
+
+// Level picked by `--level <path in assets>` on the command line, random map otherwise
+pub fn from_args() -> Option<String> {
+    config::arg("--level")
+}
+
+#[derive(Default)]
+pub struct LevelLoader;
+
+impl AssetLoader for LevelLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let level = parse_level(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(level));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["level"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_rows_bottom_first() {
+        let level = parse_level("// top comes last\n#%^~\n.A.!\n").unwrap();
+        // Right of the left wall
+        let first = generators::FIRST_CELL + 1;
+        assert_eq!(
+            vec![
+                LevelRow {
+                    tiles: vec![],
+                    spawns: vec![(first + 1, 'A')],
+                    end: true,
+                },
+                LevelRow {
+                    tiles: vec![
+                        (first, LevelTile::Solid),
+                        (first + 1, LevelTile::Destructible),
+                        (first + 2, LevelTile::Hazardous),
+                        (first + 3, LevelTile::Decorative),
+                    ],
+                    spawns: vec![],
+                    end: false,
+                },
+            ],
+            level.rows
+        );
+    }
+
+    #[test]
+    fn parse_keeps_empty_rows() {
+        let level = parse_level("#\n\n   \n").unwrap();
+        assert_eq!(3, level.rows.len());
+        assert!(level.rows[0].tiles.is_empty());
+        assert!(level.rows[1].tiles.is_empty());
+        assert_eq!(
+            vec![(generators::FIRST_CELL + 1, LevelTile::Solid)],
+            level.rows[2].tiles
+        );
+    }
+
+    #[test]
+    fn level_tiles_accept_their_kind() {
+        assert!(LevelTile::Solid.accepts(&TileKind::Solid));
+        assert!(LevelTile::Destructible.accepts(&TileKind::Destructible { score: 5 }));
+        assert!(!LevelTile::Destructible.accepts(&TileKind::Solid));
+        assert!(!LevelTile::Solid.accepts(&TileKind::Hazardous));
+        assert!(LevelTile::Decorative.accepts(&TileKind::Decorative));
+    }
+
+    #[test]
+    fn parse_error_points_at_unknown_tile() {
+        let error = parse_level("#..#\n//comment\n..#x#\n").err().unwrap();
+        assert_eq!(3, error.line);
+        assert_eq!("line 3: unknown tile 'x' in column 4", error.to_string());
+    }
+
+    #[test]
+    fn parse_error_on_too_wide_row() {
+        let wide = "#".repeat(COLUMNS + 1);
+        let error = parse_level(&format!("#\n{}", wide)).err().unwrap();
+        assert_eq!(2, error.line);
+        assert!(error.message.ends_with(&format!("column {}", COLUMNS + 1)));
+        assert!(parse_level(&"#".repeat(COLUMNS)).is_ok());
+    }
+
+    #[test]
+    fn parse_error_on_empty_level() {
+        assert!(parse_level("// nothing here\n").is_err());
+    }
+
+    #[test]
+    fn example_level_parses() {
+        let level = parse_level(include_str!("../assets/levels/first.level")).unwrap();
+        assert!(level.rows.last().unwrap().end);
+    }
+}
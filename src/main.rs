@@ -1,18 +1,38 @@
 use bevy::{prelude::*, render::settings::WgpuSettings};
 
+mod animation;
+mod archetypes;
+mod background;
+mod behaviors;
+mod bosses;
+mod broadphase;
 mod camera;
 mod collision;
 mod config;
 mod debug;
 mod enemies;
+mod formations;
+mod generators;
+mod health;
+mod level;
+mod parse;
 mod player;
+mod rng;
+mod spritesheet;
+mod tileset;
 mod ui;
+mod waves;
+mod weapons;
 use bevy_prototype_debug_lines::*;
 
 mod map;
 
 fn setup(mut commands: Commands, _asset_server: Res<AssetServer>) {
-    commands.spawn_bundle(Camera2dBundle::default());
+    // The default camera sees nothing under z zero, keep decorative tiles and the
+    // backgrounds in view
+    let mut camera = Camera2dBundle::new_with_far(2000.0);
+    camera.transform.translation.z = 999.9;
+    commands.spawn_bundle(camera);
 }
 
 fn main() {
@@ -29,11 +49,28 @@ fn main() {
             ..default()
         })
         .add_plugins(DefaultPlugins)
+        // After the log plugin so the seed shows up
+        .insert_resource(rng::GameRng::from_args())
+        .add_plugin(collision::CollisionPlugin)
+        .add_plugin(health::HealthPlugin)
         .add_plugin(player::PlayerPlugin)
+        .add_plugin(archetypes::ArchetypesPlugin)
         .add_plugin(enemies::EnemiesPlugin)
+        .add_plugin(weapons::WeaponsPlugin)
+        .add_plugin(waves::WavesPlugin)
+        .add_plugin(formations::FormationsPlugin)
+        .add_plugin(bosses::BossesPlugin)
         .add_plugin(debug::DebugPlugin)
         .add_plugin(ui::UiPlugin)
-        .add_plugin(map::MapPlugin)
+        .add_plugin(level::LevelPlugin)
+        .add_plugin(spritesheet::SpriteSheetPlugin)
+        .add_plugin(animation::AnimationPlugin)
+        .add_plugin(tileset::TilesetPlugin)
+        .add_plugin(background::BackgroundPlugin)
+        .add_plugin(map::MapPlugin {
+            generator: generators::from_args(),
+            level: level::from_args(),
+        })
         .add_startup_system(setup)
         .add_system(bevy::window::close_on_esc)
         .add_plugin(DebugLinesPlugin::default())
@@ -1,18 +1,195 @@
+use crate::archetypes;
+use crate::behaviors::Behavior;
 use crate::config;
-use crate::enemies::Enemy;
-use bevy::prelude::*;
+use crate::enemies::{self, Enemy};
+use crate::formations::Follower;
+use crate::generators;
+use crate::level;
+use crate::rng;
+use crate::spritesheet::{self, SheetSprites};
+use crate::tileset;
+use bevy::{
+    app::AppExit,
+    asset::{HandleId, LoadState},
+    prelude::*,
+    utils::{HashMap, HashSet},
+};
 //use bevy_prototype_debug_lines::*;
 use pathfinding::prelude::astar;
-use rand::Rng;
+use rand::{distributions::WeightedIndex, rngs::StdRng, Rng};
 
-pub struct MapPlugin;
+pub struct MapPlugin {
+    // Makes the generator deciding the layout of new rows
+    pub generator: fn() -> Box<dyn generators::RowGenerator>,
+    // Asset path of a level to play instead of generating rows
+    pub level: Option<String>,
+}
 
-#[derive(Default)]
 pub struct Map {
-    handles: Vec<HandleUntyped>,
+    tileset: Handle<tileset::Tileset>,
+    // Tiles of the tileset once it is loaded
+    tiles: Vec<tileset::TilesetTile>,
     pub scroll_speed: f32,
+    pub grid: MapGrid,
+    generator: Box<dyn generators::RowGenerator>,
+    // Middle tile cells of the last generated rows, the newest is last
+    generated_rows: Vec<Vec<i32>>,
+    generated_count: u32,
+    level_path: Option<String>,
+    pub level: Option<Handle<level::Level>>,
+    level_ended: bool,
+}
+
+impl Map {
+    // Index of a random tile of the tileset by weight, only from kinds the filter accepts.
+    // Tiles without weight only come up when the filter accepts nothing else, None if it
+    // accepts no tile at all.
+    fn random_tile(&self, rng: &mut StdRng, filter: impl Fn(&TileKind) -> bool) -> Option<usize> {
+        let tiles: Vec<usize> = (0..self.tiles.len())
+            .filter(|index| filter(&self.tiles[*index].kind))
+            .collect();
+        match WeightedIndex::new(tiles.iter().map(|index| self.tiles[*index].weight)) {
+            Ok(weights) => Some(tiles[rng.sample(weights)]),
+            Err(_) => tiles.first().copied(),
+        }
+    }
+}
+
+// Occupancy of the map kept up to date as tiles spawn, scroll and despawn
+#[derive(Default)]
+pub struct MapGrid {
+    // How far the map scrolled since the start
+    scrolled: f32,
+    // Tiles by their cell in the unscrolled map (cell zero,zero is at world zero,zero
+    // before any scrolling) so the index doesn't change while the map scrolls
+    tiles: HashMap<IVec2, Vec<Entity>>,
+    // Cells whose neighbours changed since the last take_dirty
+    dirty: HashSet<IVec2>,
+}
+
+impl MapGrid {
+    fn tile_cell(&self, translation: Vec3) -> IVec2 {
+        (Vec2::new(translation.x, translation.y + self.scrolled) / config::TILE_SIDE)
+            .round()
+            .as_ivec2()
+    }
+
+    // Whole rows scrolled so far, Pos moves with the screen but cells don't
+    fn scrolled_rows(&self) -> i32 {
+        // Matches the halves up rounding in Pos::from_world_vec3
+        (self.scrolled / config::TILE_SIDE - 0.5).ceil() as i32
+    }
+
+    fn pos_cell(&self, pos: &Pos) -> IVec2 {
+        IVec2::new(
+            pos.x - config::TILES_PER_WIDTH / 2,
+            pos.y - config::ROWS_PER_HEIGHT / 2 + self.scrolled_rows(),
+        )
+    }
+
+    fn cell_pos(&self, cell: &IVec2) -> Pos {
+        Pos {
+            x: cell.x + config::TILES_PER_WIDTH / 2,
+            y: cell.y + config::ROWS_PER_HEIGHT / 2 - self.scrolled_rows(),
+        }
+    }
+
+    fn add_tile(&mut self, tile: Entity, translation: Vec3) {
+        let cell = self.tile_cell(translation);
+        self.tiles.entry(cell).or_default().push(tile);
+        self.mark_dirty(cell);
+    }
+
+    pub fn remove_tile(&mut self, tile: Entity, translation: Vec3) {
+        let cell = self.tile_cell(translation);
+        if let Some(tiles) = self.tiles.get_mut(&cell) {
+            tiles.retain(|t| *t != tile);
+            if tiles.is_empty() {
+                self.tiles.remove(&cell);
+            }
+            self.mark_dirty(cell);
+        }
+    }
+
+    fn mark_dirty(&mut self, cell: IVec2) {
+        self.dirty.insert(cell);
+        for (offset, _) in NEIGHBOURS {
+            self.dirty.insert(cell + offset);
+        }
+    }
+
+    // Cells that were changed or had a neighbour change, each only once
+    pub fn take_dirty(&mut self) -> Vec<IVec2> {
+        self.dirty.drain().collect()
+    }
+
+    pub fn tiles_at(&self, cell: IVec2) -> &[Entity] {
+        self.tiles.get(&cell).map_or(&[], |tiles| tiles.as_slice())
+    }
+
+    // Sum of the tileset::AUTOTILE_* sides that have a tile next to the cell
+    pub fn neighbour_mask(&self, cell: IVec2) -> usize {
+        NEIGHBOURS
+            .iter()
+            .filter(|(offset, _)| self.tiles.contains_key(&(cell + *offset)))
+            .map(|(_, side)| side)
+            .sum()
+    }
+
+    fn scroll(&mut self, distance: f32) {
+        self.scrolled += distance;
+    }
+
+    pub fn is_free(&self, pos: Pos) -> bool {
+        !self.tiles.contains_key(&self.pos_cell(&pos))
+    }
+
+    // Every position with at least one tile on it, in no particular order
+    #[allow(dead_code)]
+    pub fn occupied(&self) -> impl Iterator<Item = Pos> + '_ {
+        self.tiles.keys().map(|cell| self.cell_pos(cell))
+    }
+
+    // All positions in the inclusive rect (min, max) row by row together with whether
+    // they are free
+    pub fn cells(&self, min: Pos, max: Pos) -> impl Iterator<Item = (Pos, bool)> + '_ {
+        (min.y..=max.y).flat_map(move |y| {
+            (min.x..=max.x).map(move |x| {
+                let pos = Pos { x, y };
+                (pos, self.is_free(pos))
+            })
+        })
+    }
+
+    // Tiles whose cell touches the world space rect (min, max)
+    pub fn tiles_in_rect(&self, min: Vec2, max: Vec2) -> Vec<Entity> {
+        let scrolled = Vec2::new(0.0, self.scrolled);
+        // Tiles are centered on their cell
+        let min_cell = ((min + scrolled) / config::TILE_SIDE + 0.5)
+            .floor()
+            .as_ivec2();
+        let max_cell = ((max + scrolled) / config::TILE_SIDE + 0.5)
+            .floor()
+            .as_ivec2();
+        let mut found: Vec<Entity> = Vec::new();
+        for y in min_cell.y..=max_cell.y {
+            for x in min_cell.x..=max_cell.x {
+                if let Some(tiles) = self.tiles.get(&IVec2::new(x, y)) {
+                    found.extend(tiles);
+                }
+            }
+        }
+        found
+    }
 }
 
+const NEIGHBOURS: [(IVec2, usize); 4] = [
+    (IVec2::new(0, 1), tileset::AUTOTILE_NORTH),
+    (IVec2::new(1, 0), tileset::AUTOTILE_EAST),
+    (IVec2::new(0, -1), tileset::AUTOTILE_SOUTH),
+    (IVec2::new(-1, 0), tileset::AUTOTILE_WEST),
+];
+
 //TODO(amatej): this should be u32 so its clear we have to convert back to map coors... that have
 //zero,zero in the center not bottom left like Pos
 //TODO(amatej): this represents position of a tile in the tiled world?
@@ -31,7 +208,9 @@ impl Pos {
     }
 
     pub fn from_world_vec3(input: &Vec3) -> Pos {
-        let mut pos = (*input / Vec3::new(config::TILE_SIDE, config::TILE_SIDE, 1.0)).round();
+        // Round halves up (not away from zero) so a position keeps its rounding when
+        // shifted by whole tiles, MapGrid relies on this while scrolling
+        let mut pos = (*input / Vec3::new(config::TILE_SIDE, config::TILE_SIDE, 1.0) + 0.5).floor();
         pos.x = pos.x + (config::TILES_PER_WIDTH / 2) as f32;
         pos.y = pos.y + (config::ROWS_PER_HEIGHT / 2) as f32;
         //TODO(amatej): Fix this ugliness
@@ -81,6 +260,23 @@ struct ToBeProcessedRow;
 #[derive(Component)]
 pub struct Tile;
 
+// Tile whose texture follows its neighbours, index of its tile in Map::tiles
+#[derive(Component)]
+struct Autotile(usize);
+
+// What a tile does, comes from the tileset
+#[derive(Component, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TileKind {
+    // Wall the ship crashes into
+    Solid,
+    // Rock shots can break for score
+    Destructible { score: usize },
+    // Background that doesn't collide with anything
+    Decorative,
+    // Takes health while the ship touches it
+    Hazardous,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 enum PluginState {
     Loading,
@@ -90,8 +286,16 @@ enum PluginState {
 impl Plugin for MapPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Map {
-            handles: Vec::new(),
+            tileset: Handle::default(),
+            tiles: Vec::new(),
             scroll_speed: config::SCROLL_SPEED,
+            grid: MapGrid::default(),
+            generator: (self.generator)(),
+            generated_rows: Vec::new(),
+            generated_count: 0,
+            level_path: self.level.clone(),
+            level: None,
+            level_ended: false,
         });
         app.add_state(PluginState::Loading);
         app.add_system_set(SystemSet::on_enter(PluginState::Loading).with_system(load_resources));
@@ -99,16 +303,36 @@ impl Plugin for MapPlugin {
         app.add_system_set(SystemSet::on_enter(PluginState::Loaded).with_system(setup));
         app.add_system(scroll_map_system);
         app.add_system(generate_map_system);
+        // After the tiles spawned this frame exist
+        app.add_system_to_stage(CoreStage::PostUpdate, autotile_system);
     }
 }
 
+// Enemies finding their own way, formation followers go after their leader
+type PathfindingQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static mut Transform,
+        &'static mut Enemy,
+        &'static Behavior,
+    ),
+    (Without<Tile>, Without<Follower>),
+>;
+
+#[allow(clippy::too_many_arguments)]
 fn generate_map_system(
     mut commands: Commands,
-    map: Res<Map>,
+    mut map: ResMut<Map>,
+    mut rng: ResMut<rng::GameRng>,
+    levels: Res<Assets<level::Level>>,
+    sprites: SheetSprites,
+    archetypes: Res<archetypes::Archetypes>,
+    archetype_assets: Res<Assets<archetypes::EnemyArchetypes>>,
+    mut level_end_events: EventWriter<level::LevelEnd>,
     //mut lines: ResMut<DebugLines>,
     row_query: Query<(Entity, &Row), With<ToBeProcessedRow>>,
-    tile_query: Query<&Transform, With<Tile>>,
-    mut query: Query<(&mut Transform, &mut Enemy), Without<Tile>>,
+    mut query: PathfindingQuery,
 ) {
     if row_query.is_empty() {
         return;
@@ -120,61 +344,84 @@ fn generate_map_system(
 
     // Spawn sides
     for side in vec![-config::MAP_BOUNDS.x / 2.0, config::MAP_BOUNDS.x / 2.0] {
-        let random_tile_index = rand::thread_rng().gen_range(0..(map.handles.len()));
-        commands
-            .spawn_bundle(SpriteBundle {
-                transform: Transform::from_translation(Vec3::new(side, row.y_pos, 0.0)),
-                texture: map.handles[random_tile_index].typed_weak(),
-                ..Default::default()
-            })
-            .insert(Tile);
-    }
-
-    // Spawn middle per chance
-    let from = -(config::MAP_BOUNDS.x / 2.0) as i32;
-    let to = (config::MAP_BOUNDS.x / 2.0) as i32;
-    let mut random_change_offset = 0;
-    //println!("from {:?} to: {:?}", from, to);
-    for pos in (from..to).step_by(config::TILE_SIDE as usize) {
-        //println!("pos: {:?}", pos/32);
-        let random_chance = rand::thread_rng().gen_range(0..100);
-        //println!("random chance: {:?}", random_chance);
-        if random_chance > 98 - random_change_offset {
-            if random_change_offset == 0 {
-                random_change_offset = 80;
-            } else {
-                random_change_offset = random_change_offset - 20;
-            }
-
-            let random_tile_index = rand::thread_rng().gen_range(0..(map.handles.len()));
-            commands
-                .spawn_bundle(SpriteBundle {
-                    transform: Transform::from_translation(Vec3::new(pos as f32, row.y_pos, 0.0)),
-                    texture: map.handles[random_tile_index].typed_weak(),
-                    ..Default::default()
-                })
-                .insert(Tile);
-        } else {
-            random_change_offset = 0;
-        }
+        spawn_tile(
+            &mut commands,
+            &mut map,
+            &mut rng.map,
+            Vec3::new(side, row.y_pos, 0.0),
+            |kind| *kind == TileKind::Solid,
+        );
     }
 
-    // construct current map:
-    // add +2 for debug
-    let mut map_maze: [[bool; config::TILES_PER_WIDTH as usize + 2];
-        config::ROWS_PER_HEIGHT as usize + 2] =
-        [[true; config::TILES_PER_WIDTH as usize + 2]; config::ROWS_PER_HEIGHT as usize + 2];
-    for tile_trans in &tile_query {
-        let mp = Pos::from_world_vec3(&tile_trans.translation);
-        map_maze[mp.y as usize][mp.x as usize] = false;
+    // Spawn middle from the level or by the active generator
+    let map = &mut *map;
+    let cells = match map.level.as_ref().and_then(|handle| levels.get(handle)) {
+        Some(level) => match level.rows.get(map.generated_count as usize) {
+            Some(level_row) if !map.level_ended => {
+                for (cell, kind) in &level_row.spawns {
+                    let translation = Vec3::new(*cell as f32 * config::TILE_SIDE, row.y_pos, 0.0);
+                    // Loaded together with the level
+                    let archetypes = archetype_assets.get(&archetypes.0).unwrap();
+                    match archetypes.get(*kind) {
+                        Some(archetype) => {
+                            let speed = archetype.random_speed(&mut rng.ai);
+                            enemies::spawn_enemy(
+                                &mut commands,
+                                &sprites,
+                                archetype,
+                                translation,
+                                speed,
+                                Vec::new(),
+                            );
+                        }
+                        None => warn!("No enemy archetype for kind {} in the level", kind),
+                    }
+                }
+                if level_row.end {
+                    map.level_ended = true;
+                    level_end_events.send(level::LevelEnd);
+                }
+                level_row
+                    .tiles
+                    .iter()
+                    .map(|(cell, level_tile)| (*cell, Some(*level_tile)))
+                    .collect()
+            }
+            // Open space after the level
+            _ => Vec::new(),
+        },
+        // Generated rows get random tiles of any kind
+        None => map
+            .generator
+            .generate(map.generated_count, &mut rng.map, &map.generated_rows)
+            .into_iter()
+            .map(|cell| (cell, None))
+            .collect(),
+    };
+    for (cell, level_tile) in &cells {
+        spawn_tile(
+            &mut commands,
+            map,
+            &mut rng.map,
+            Vec3::new(*cell as f32 * config::TILE_SIDE, row.y_pos, 0.0),
+            |kind| level_tile.is_none_or(|level_tile| level_tile.accepts(kind)),
+        );
     }
+    let cells: Vec<i32> = cells.iter().map(|(cell, _)| *cell).collect();
+    map.generated_count += 1;
+    map.generated_rows.push(cells);
+    if map.generated_rows.len() > config::ROWS_PER_HEIGHT as usize {
+        map.generated_rows.remove(0);
+    }
+
+    let grid = &map.grid;
 
     // Debug draw the map so we can see what we have
     //for xi in 0..config::TILES_PER_WIDTH + 1 {
     //    for yi in 0..config::ROWS_PER_HEIGHT + 1 {
     //        let p = Pos { 0: xi, 1: yi };
     //        let c: Color;
-    //        if map_maze[yi as usize][xi as usize] {
+    //        if grid.is_free(p) {
     //            c = Color::GREEN
     //        } else {
     //            c = Color::RED
@@ -194,118 +441,11 @@ fn generate_map_system(
     //    }
     //}
 
-    for (trans, mut enemy) in &mut query {
-        if !enemy.path.is_empty() {
+    for (trans, mut enemy, behavior) in &mut query {
+        if !enemy.path.is_empty() || *behavior != Behavior::PathFollow {
             continue;
         }
 
-        let successors = |input: Pos| -> Vec<(Pos, u32)> {
-            let mut sucs: Vec<Pos> = vec![];
-            for (new_pos_offset, new_space_taken_offsets) in vec![
-                (
-                    Pos { x: 1, y: 1 },
-                    vec![
-                        Pos { x: 0, y: 2 },
-                        Pos { x: 1, y: 2 },
-                        Pos { x: 2, y: 2 },
-                        Pos { x: 2, y: 1 },
-                        Pos { x: 1, y: 0 },
-                    ],
-                ),
-                (
-                    Pos { x: 1, y: 0 },
-                    vec![Pos { x: 2, y: 1 }, Pos { x: 2, y: 0 }, Pos { x: 2, y: -1 }],
-                ),
-                (
-                    Pos { x: 1, y: -1 },
-                    vec![
-                        Pos { x: 2, y: 0 },
-                        Pos { x: 2, y: -1 },
-                        Pos { x: 2, y: -2 },
-                        Pos { x: 1, y: -2 },
-                        Pos { x: 0, y: -2 },
-                    ],
-                ),
-                (
-                    Pos { x: 0, y: 1 },
-                    vec![Pos { x: -1, y: 2 }, Pos { x: 0, y: 2 }, Pos { x: 1, y: 2 }],
-                ),
-                (
-                    Pos { x: 0, y: -1 },
-                    vec![
-                        Pos { x: -1, y: -2 },
-                        Pos { x: 0, y: -2 },
-                        Pos { x: 1, y: -2 },
-                    ],
-                ),
-                (
-                    Pos { x: -1, y: 1 },
-                    vec![
-                        Pos { x: -2, y: 0 },
-                        Pos { x: -2, y: 1 },
-                        Pos { x: -2, y: 2 },
-                        Pos { x: -1, y: 2 },
-                        Pos { x: 0, y: 2 },
-                    ],
-                ),
-                (
-                    Pos { x: -1, y: 0 },
-                    vec![
-                        Pos { x: -2, y: 1 },
-                        Pos { x: -2, y: 0 },
-                        Pos { x: -2, y: -1 },
-                    ],
-                ),
-                (
-                    Pos { x: -1, y: -1 },
-                    vec![
-                        Pos { x: -2, y: 0 },
-                        Pos { x: -2, y: -1 },
-                        Pos { x: -2, y: -2 },
-                        Pos { x: -1, y: -2 },
-                        Pos { x: 0, y: -2 },
-                    ],
-                ),
-            ] {
-                // check if the actual position we are moving to is available
-                let new_pos = &input + &new_pos_offset;
-                if new_pos.x >= config::TILES_PER_WIDTH
-                    || new_pos.x < 0
-                    || new_pos.y >= config::ROWS_PER_HEIGHT
-                    || new_pos.y < 0
-                {
-                    continue;
-                }
-                if !map_maze[new_pos.y as usize][new_pos.x as usize] {
-                    continue;
-                }
-
-                // In case we are bigger than one TILE check if other parts fit as well
-                let mut will_fit: bool = true;
-                for offset in new_space_taken_offsets {
-                    let testing_pos = &offset + &input;
-                    // Befora I push I should check if I am outside of map -> and if so not push!
-                    if testing_pos.x >= config::TILES_PER_WIDTH
-                        || testing_pos.x < 0
-                        || testing_pos.y >= config::ROWS_PER_HEIGHT
-                        || testing_pos.y < 0
-                    {
-                        will_fit = false;
-                        break;
-                    }
-                    if !map_maze[testing_pos.y as usize][testing_pos.x as usize] {
-                        will_fit = false;
-                        break;
-                    }
-                }
-
-                if will_fit {
-                    sucs.push(new_pos);
-                }
-            }
-            //println!("sucs: {:?}", sucs);
-            sucs.into_iter().map(|p| (p, 1)).collect()
-        };
         let my_pos = Pos::from_world_vec3(&trans.translation);
         //lines.line_colored(
         //    my_pos.to_world_vec3() + Vec3::new(10.0, 10.0, 0.0),
@@ -320,7 +460,7 @@ fn generate_map_system(
         //    Color::RED,
         //);
 
-        //let sucs = successors(my_pos);
+        //let sucs = successors(grid, my_pos, enemy.size);
         //for s in sucs {
         //    let p = s.0;
         //    lines.line_colored(
@@ -340,17 +480,58 @@ fn generate_map_system(
         let goal: Pos = Pos { x: my_pos.x, y: 2 };
         let result = astar(
             &my_pos,
-            |p| successors(*p),
+            |p| successors(grid, *p, enemy.size),
             |p| p.distance(&goal),
             |p| (*p) == goal,
         );
         //println!("result is: {:?}", result);
         if let Some(t) = result {
             enemy.path = prune_path(&t.0);
+            // The grid scrolls with the tiles so the new path is where the map is now, the
+            // offset only counts the scrolling from here on
+            enemy.scroll_offset = Vec3::ZERO;
         }
     }
 }
 
+// Whether all of a ship size tiles wide fits inside the map on free tiles at pos
+pub fn fits(grid: &MapGrid, pos: Pos, size: i32) -> bool {
+    // Tiles the ship takes around its position, bigger to the right and up for even sizes
+    let (low, high) = ((1 - size) / 2, size / 2);
+    (low..=high).all(|y| {
+        (low..=high).all(|x| {
+            let testing_pos = &pos + &Pos { x, y };
+            testing_pos.x < config::TILES_PER_WIDTH
+                && testing_pos.x >= 0
+                && testing_pos.y < config::ROWS_PER_HEIGHT
+                && testing_pos.y >= 0
+                && grid.is_free(testing_pos)
+        })
+    })
+}
+
+// Positions a ship size tiles wide can move to from input in one step
+fn successors(grid: &MapGrid, input: Pos, size: i32) -> Vec<(Pos, u32)> {
+    let mut sucs: Vec<Pos> = vec![];
+    for new_pos_offset in [
+        Pos { x: 1, y: 1 },
+        Pos { x: 1, y: 0 },
+        Pos { x: 1, y: -1 },
+        Pos { x: 0, y: 1 },
+        Pos { x: 0, y: -1 },
+        Pos { x: -1, y: 1 },
+        Pos { x: -1, y: 0 },
+        Pos { x: -1, y: -1 },
+    ] {
+        let new_pos = &input + &new_pos_offset;
+        if fits(grid, new_pos, size) {
+            sucs.push(new_pos);
+        }
+    }
+    //println!("sucs: {:?}", sucs);
+    sucs.into_iter().map(|p| (p, 1)).collect()
+}
+
 // Remove points with identical delta
 fn prune_path(path: &Vec<Pos>) -> Vec<Pos> {
     if path.len() <= 2 {
@@ -384,7 +565,7 @@ fn prune_path(path: &Vec<Pos>) -> Vec<Pos> {
 }
 
 fn scroll_map_system(
-    map: Res<Map>,
+    mut map: ResMut<Map>,
     mut commands: Commands,
     mut tile_query: Query<(Entity, &mut Transform), With<Tile>>,
     mut row_query: Query<(Entity, &mut Row)>,
@@ -401,9 +582,11 @@ fn scroll_map_system(
         }
     }
 
+    map.grid.scroll(scroll_distance.y);
     for (tile, mut tile_trans) in &mut tile_query {
         tile_trans.translation -= scroll_distance;
         if tile_trans.translation.y < -config::MAP_BOUNDS.y / 2.0 - 2.0 * config::TILE_SIDE {
+            map.grid.remove_tile(tile, tile_trans.translation);
             commands.entity(tile).despawn();
         }
     }
@@ -413,37 +596,135 @@ fn scroll_map_system(
 }
 
 fn check_resources(
-    map: ResMut<Map>,
+    mut map: ResMut<Map>,
     mut state: ResMut<State<PluginState>>,
     asset_server: Res<AssetServer>,
+    tilesets: Res<Assets<tileset::Tileset>>,
+    archetypes: Res<archetypes::Archetypes>,
+    sprites: Res<spritesheet::Sprites>,
+    mut app_exit_events: EventWriter<AppExit>,
 ) {
-    if let bevy::asset::LoadState::Loaded =
-        asset_server.get_group_load_state(map.handles.iter().map(|handle| handle.id))
+    let failed = |id: HandleId| asset_server.get_load_state(id) == LoadState::Failed;
+    // A level that can't be loaded is played with the generator instead
+    if let Some(level) = map.level.as_ref().filter(|level| failed(level.id)) {
+        warn!(
+            "Couldn't load level {}, generating the map instead",
+            asset_name(&asset_server, level.id)
+        );
+        map.level = None;
+    }
+    // Nothing can be shown without the tileset, its textures and the sprites
+    let tileset = tilesets.get(&map.tileset);
+    let textures: Vec<HandleId> = tileset
+        .iter()
+        .flat_map(|tileset| tileset.tiles.iter().map(|tile| tile.atlas.id))
+        .collect();
+    let mut required = [map.tileset.id, archetypes.0.id, sprites.0.id]
+        .into_iter()
+        .chain(textures.iter().copied());
+    if let Some(id) = required.find(|id| failed(*id)) {
+        error!("Couldn't load {}", asset_name(&asset_server, id));
+        app_exit_events.send(AppExit);
+        return;
+    }
+    // Textures only start loading with the tileset
+    let tileset = match tileset {
+        Some(tileset) => tileset,
+        None => return,
+    };
+    // Levels spawn their enemies from the archetypes
+    let level = map
+        .level
+        .iter()
+        .map(|handle| handle.id)
+        .chain([archetypes.0.id])
+        // Enemies show regions of the sprites sheet
+        .chain([sprites.0.id]);
+    if let LoadState::Loaded = asset_server.get_group_load_state(textures.into_iter().chain(level))
     {
+        map.tiles = tileset.tiles.clone();
         state.set(PluginState::Loaded).unwrap();
     }
 }
 
+// Path of an asset with its label for messages
+fn asset_name(asset_server: &AssetServer, id: HandleId) -> String {
+    match asset_server.get_handle_path(id) {
+        Some(path) => match path.label() {
+            Some(label) => format!("{}#{}", path.path().display(), label),
+            None => path.path().display().to_string(),
+        },
+        None => format!("{:?}", id),
+    }
+}
+
 fn load_resources(mut map: ResMut<Map>, asset_server: Res<AssetServer>) {
-    map.handles = asset_server.load_folder("textures/tiles").unwrap();
+    map.tileset = asset_server.load("textures/tiles/tiles.tileset");
+    if let Some(path) = map.level_path.clone() {
+        map.level = Some(asset_server.load(&path));
+    }
+}
+
+fn spawn_tile(
+    commands: &mut Commands,
+    map: &mut Map,
+    rng: &mut StdRng,
+    mut translation: Vec3,
+    filter: impl Fn(&TileKind) -> bool,
+) {
+    // Tilesets always have a solid tile to fall back on
+    let index = map
+        .random_tile(rng, filter)
+        .or_else(|| map.random_tile(rng, |kind| *kind == TileKind::Solid))
+        .unwrap();
+    let tile = &map.tiles[index];
+    if tile.kind == TileKind::Decorative {
+        // Keep the background under ships and shots
+        translation.z = -0.5;
+    }
+    let mut entity = commands.spawn_bundle(SpriteSheetBundle {
+        sprite: TextureAtlasSprite::new(tile.index),
+        texture_atlas: tile.atlas.clone_weak(),
+        transform: Transform::from_translation(translation),
+        ..Default::default()
+    });
+    entity.insert(Tile).insert(tile.kind);
+    if !tile.variants.is_empty() {
+        entity.insert(Autotile(index));
+    }
+    let (entity, kind) = (entity.id(), tile.kind);
+    // Nothing can run into decorative tiles so they don't take space in the grid
+    if kind != TileKind::Decorative {
+        map.grid.add_tile(entity, translation);
+    }
 }
 
-fn setup(mut commands: Commands, map: Res<Map>) {
+// Picks the variant of autotiled tiles matching their neighbours whenever these change
+fn autotile_system(
+    mut map: ResMut<Map>,
+    mut tile_query: Query<(&Autotile, &mut TextureAtlasSprite)>,
+) {
+    for cell in map.grid.take_dirty() {
+        let mask = map.grid.neighbour_mask(cell);
+        for tile in map.grid.tiles_at(cell) {
+            if let Ok((autotile, mut sprite)) = tile_query.get_mut(*tile) {
+                sprite.index = map.tiles[autotile.0].variants[mask];
+            }
+        }
+    }
+}
+
+fn setup(mut commands: Commands, mut map: ResMut<Map>, mut rng: ResMut<rng::GameRng>) {
     // spawn side map bounds
     for side in vec![-config::MAP_BOUNDS.x / 2.0, config::MAP_BOUNDS.x / 2.0] {
         for pos in (-config::ROWS_PER_HEIGHT / 2)..(config::ROWS_PER_HEIGHT / 2) {
-            let random_tile_index = rand::thread_rng().gen_range(0..(map.handles.len()));
-            commands
-                .spawn_bundle(SpriteBundle {
-                    transform: Transform::from_translation(Vec3::new(
-                        side,
-                        pos as f32 * config::TILE_SIDE,
-                        0.0,
-                    )),
-                    texture: map.handles[random_tile_index].typed_weak(),
-                    ..Default::default()
-                })
-                .insert(Tile);
+            spawn_tile(
+                &mut commands,
+                &mut map,
+                &mut rng.map,
+                Vec3::new(side, pos as f32 * config::TILE_SIDE, 0.0),
+                |kind| *kind == TileKind::Solid,
+            );
         }
     }
 
@@ -458,6 +739,7 @@ fn setup(mut commands: Commands, map: Res<Map>) {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use rand::SeedableRng;
 
     #[test]
     fn prune_test() {
@@ -500,4 +782,286 @@ mod tests {
             prune_path(&path)
         );
     }
+
+    #[test]
+    fn tiles_in_rect_finds_only_touched_cells() {
+        let mut grid = MapGrid::default();
+        let side = config::TILE_SIDE;
+        let near = Entity::from_raw(1);
+        let far = Entity::from_raw(2);
+        grid.add_tile(near, Vec3::new(side, 0.0, 0.0));
+        grid.add_tile(far, Vec3::new(4.0 * side, 0.0, 0.0));
+
+        let found = grid.tiles_in_rect(Vec2::new(side * 0.6, -1.0), Vec2::new(side * 0.7, 1.0));
+        assert_eq!(vec![near], found);
+        // Rect ends just before the near tile's cell
+        let found = grid.tiles_in_rect(Vec2::new(-side, -1.0), Vec2::new(side * 0.4, 1.0));
+        assert!(found.is_empty());
+    }
+
+    #[test]
+    fn tiles_in_rect_follows_scrolling() {
+        let mut grid = MapGrid::default();
+        let side = config::TILE_SIDE;
+        let tile = Entity::from_raw(1);
+        grid.add_tile(tile, Vec3::new(0.0, 2.0 * side, 0.0));
+
+        // Move the grid the same way scroll_map_system moves the tile transforms
+        grid.scroll(1.5 * side);
+        let scrolled = Vec3::new(0.0, 0.5 * side, 0.0);
+        let found = grid.tiles_in_rect(Vec2::new(-1.0, scrolled.y), Vec2::new(1.0, scrolled.y));
+        assert_eq!(vec![tile], found);
+        let found = grid.tiles_in_rect(Vec2::new(-1.0, 2.0 * side), Vec2::new(1.0, 2.0 * side));
+        assert!(found.is_empty());
+
+        grid.remove_tile(tile, scrolled);
+        assert!(grid.tiles.is_empty());
+    }
+
+    #[test]
+    fn is_free_matches_tile_positions() {
+        let mut grid = MapGrid::default();
+        let translation = Vec3::new(3.0 * config::TILE_SIDE, -config::TILE_SIDE, 0.0);
+        grid.add_tile(Entity::from_raw(1), translation);
+
+        let pos = Pos::from_world_vec3(&translation);
+        assert!(!grid.is_free(pos));
+        assert!(grid.is_free(&pos + &Pos { x: 1, y: 0 }));
+        assert!(grid.is_free(&pos + &Pos { x: 0, y: 1 }));
+    }
+
+    #[test]
+    fn is_free_follows_scrolling() {
+        let mut grid = MapGrid::default();
+        let tile = Entity::from_raw(1);
+        let mut translation = Vec3::new(0.0, 5.0 * config::TILE_SIDE, 0.0);
+        grid.add_tile(tile, translation);
+
+        // Scroll by small steps and check the tile is where its transform says
+        for _ in 0..100 {
+            // Quarter tiles hit the rounding halfway between rows too
+            let distance = 0.25 * config::TILE_SIDE;
+            grid.scroll(distance);
+            translation.y -= distance;
+            let pos = Pos::from_world_vec3(&translation);
+            assert!(!grid.is_free(pos), "{:?} should be taken", pos);
+            assert!(grid.is_free(&pos + &Pos { x: 0, y: 1 }));
+            assert!(grid.is_free(&pos + &Pos { x: 0, y: -1 }));
+            if translation.y < -config::MAP_BOUNDS.y / 2.0 {
+                break;
+            }
+        }
+
+        grid.remove_tile(tile, translation);
+        assert!(grid.is_free(Pos::from_world_vec3(&translation)));
+    }
+
+    #[test]
+    fn occupied_lists_each_taken_position_once() {
+        let mut grid = MapGrid::default();
+        let a = Vec3::new(0.0, 0.0, 0.0);
+        let b = Vec3::new(config::TILE_SIDE, 2.0 * config::TILE_SIDE, 0.0);
+        grid.add_tile(Entity::from_raw(1), a);
+        grid.add_tile(Entity::from_raw(2), a);
+        grid.add_tile(Entity::from_raw(3), b);
+
+        let mut occupied: Vec<Pos> = grid.occupied().collect();
+        occupied.sort();
+        assert_eq!(
+            vec![Pos::from_world_vec3(&a), Pos::from_world_vec3(&b)],
+            occupied
+        );
+    }
+
+    #[test]
+    fn cells_iterate_rect_rows() {
+        let mut grid = MapGrid::default();
+        let taken = Pos { x: 5, y: 6 };
+        grid.add_tile(Entity::from_raw(1), taken.to_world_vec3());
+
+        let cells: Vec<(Pos, bool)> = grid.cells(Pos { x: 4, y: 5 }, Pos { x: 5, y: 6 }).collect();
+        assert_eq!(
+            vec![
+                (Pos { x: 4, y: 5 }, true),
+                (Pos { x: 5, y: 5 }, true),
+                (Pos { x: 4, y: 6 }, true),
+                (Pos { x: 5, y: 6 }, false),
+            ],
+            cells
+        );
+    }
+
+    // Screen full of rows of the generator (bottom one first) with the side walls
+    fn generate_window(generator: &str, seed: u64) -> MapGrid {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let mut generator = generators::by_name(generator).unwrap()();
+        let mut rows: Vec<Vec<i32>> = Vec::new();
+        // Start from wherever the corridor wandered to
+        for index in 0..(seed % 50) as u32 {
+            rows.push(generator.generate(index, &mut rng, &rows));
+        }
+        let mut grid = MapGrid::default();
+        let mut tiles = 0;
+        for y in 0..config::ROWS_PER_HEIGHT {
+            let row_y = Pos { x: 0, y }.to_world_vec3().y;
+            let mut cells = generator.generate(rows.len() as u32, &mut rng, &rows);
+            rows.push(cells.clone());
+            cells.extend([generators::FIRST_CELL, -generators::FIRST_CELL]);
+            for cell in cells {
+                tiles += 1;
+                let x = cell as f32 * config::TILE_SIDE;
+                grid.add_tile(Entity::from_raw(tiles), Vec3::new(x, row_y, 0.0));
+            }
+        }
+        grid
+    }
+
+    #[test]
+    fn generated_map_is_passable() {
+        for generator in generators::GENERATORS {
+            for seed in 0..200 {
+                let grid = generate_window(generator, seed);
+                // From some place at the top a ship fits in to the bottom
+                let top = config::ROWS_PER_HEIGHT - 2;
+                let passable = (0..config::TILES_PER_WIDTH)
+                    .map(|x| Pos { x, y: top })
+                    .filter(|start| fits(&grid, *start, 3))
+                    .any(|start| {
+                        astar(
+                            &start,
+                            |p| successors(&grid, *p, 3),
+                            |p| p.y.abs_diff(1),
+                            |p| p.y == 1,
+                        )
+                        .is_some()
+                    });
+                assert!(passable, "{} seed {} has no way through", generator, seed);
+            }
+        }
+    }
+
+    #[test]
+    fn neighbour_mask_sums_taken_sides() {
+        let mut grid = MapGrid::default();
+        let side = config::TILE_SIDE;
+        for (index, (x, y)) in [(0, 0), (0, 1), (1, 0), (-1, 0)].iter().enumerate() {
+            grid.add_tile(
+                Entity::from_raw(index as u32),
+                Vec3::new(*x as f32 * side, *y as f32 * side, 0.0),
+            );
+        }
+        assert_eq!(
+            tileset::AUTOTILE_NORTH + tileset::AUTOTILE_EAST + tileset::AUTOTILE_WEST,
+            grid.neighbour_mask(IVec2::ZERO)
+        );
+        assert_eq!(
+            tileset::AUTOTILE_SOUTH,
+            grid.neighbour_mask(IVec2::new(0, 1))
+        );
+        // Counts for empty cells too
+        assert_eq!(
+            tileset::AUTOTILE_SOUTH + tileset::AUTOTILE_WEST,
+            grid.neighbour_mask(IVec2::new(1, 1))
+        );
+    }
+
+    #[test]
+    fn removing_a_tile_dirties_its_neighbours() {
+        let mut grid = MapGrid::default();
+        let side = config::TILE_SIDE;
+        let left = Entity::from_raw(1);
+        let right = Entity::from_raw(2);
+        grid.add_tile(left, Vec3::ZERO);
+        grid.add_tile(right, Vec3::new(side, 0.0, 0.0));
+        assert_eq!(8, grid.take_dirty().len());
+        assert!(grid.take_dirty().is_empty());
+
+        grid.remove_tile(right, Vec3::new(side, 0.0, 0.0));
+        let dirty = grid.take_dirty();
+        assert_eq!(5, dirty.len());
+        assert!(dirty.contains(&IVec2::ZERO));
+        assert_eq!(0, grid.neighbour_mask(IVec2::ZERO));
+        assert_eq!(&[left], grid.tiles_at(IVec2::ZERO));
+        assert!(grid.tiles_at(IVec2::new(1, 0)).is_empty());
+    }
+
+    #[test]
+    fn successors_keep_whole_ship_off_tiles() {
+        let mut grid = MapGrid::default();
+        let start = Pos { x: 10, y: 10 };
+        // Tile two columns right of the start
+        let tile = &start + &Pos { x: 2, y: 0 };
+        grid.add_tile(Entity::from_raw(1), tile.to_world_vec3());
+        let moves = |size: i32| -> Vec<Pos> {
+            successors(&grid, start, size)
+                .into_iter()
+                .map(|(pos, _)| pos)
+                .collect()
+        };
+        assert_eq!(8, moves(1).len());
+        // Three wide ships can't move towards the tile at all
+        assert_eq!(5, moves(3).len());
+        assert!(moves(3).iter().all(|pos| pos.x <= start.x));
+        // Five wide ones overlap the tile unless they move away from it
+        assert_eq!(3, moves(5).len());
+        // Ships stay inside the map
+        assert_eq!(
+            3,
+            successors(&MapGrid::default(), Pos { x: 0, y: 0 }, 1).len()
+        );
+        assert_eq!(
+            3,
+            successors(&MapGrid::default(), Pos { x: 1, y: 1 }, 3).len()
+        );
+    }
+
+    #[test]
+    fn random_tile_respects_filter_and_weights() {
+        let tile = |weight: u32, kind: TileKind| tileset::TilesetTile {
+            atlas: Handle::default(),
+            index: 0,
+            weight,
+            kind,
+            variants: Vec::new(),
+        };
+        let map = Map {
+            tileset: Handle::default(),
+            tiles: vec![
+                tile(1, TileKind::Solid),
+                tile(0, TileKind::Hazardous),
+                tile(3, TileKind::Decorative),
+            ],
+            scroll_speed: 0.0,
+            grid: MapGrid::default(),
+            generator: Box::new(generators::RandomRows::default()),
+            generated_rows: Vec::new(),
+            generated_count: 0,
+            level_path: None,
+            level: None,
+            level_ended: false,
+        };
+        let mut rng = StdRng::seed_from_u64(0);
+        let kinds: Vec<TileKind> = (0..1000)
+            .map(|_| map.tiles[map.random_tile(&mut rng, |_| true).unwrap()].kind)
+            .collect();
+        assert!(!kinds.contains(&TileKind::Hazardous));
+        let solid = kinds.iter().filter(|k| **k == TileKind::Solid).count();
+        assert!(solid > 150 && solid < 350, "{} solid tiles", solid);
+        assert!((0..100).all(|_| {
+            let index = map.random_tile(&mut rng, |kind| *kind == TileKind::Solid);
+            map.tiles[index.unwrap()].kind == TileKind::Solid
+        }));
+        // Levels still get tiles without weight, but not kinds the tileset doesn't have
+        assert_eq!(
+            Some(1),
+            map.random_tile(&mut rng, |kind| *kind == TileKind::Hazardous)
+        );
+        assert_eq!(
+            None,
+            map.random_tile(&mut rng, |kind| matches!(
+                kind,
+                TileKind::Destructible { .. }
+            ))
+        );
+    }
 }
@@ -0,0 +1,41 @@
+use std::fmt;
+use std::str::FromStr;
+
+// Error of the text asset formats, the line starts at one like in text editors
+#[derive(Debug, PartialEq)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+// Field as a number, the message of the error names the field
+pub fn number<T: FromStr>(field: &str) -> Result<T, String> {
+    field
+        .parse()
+        .map_err(|_| format!("'{}' is not a number", field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn number_parses_or_names_field() {
+        assert_eq!(Ok(12), number::<u32>("12"));
+        assert_eq!(Ok(0.5), number::<f32>("0.5"));
+        assert_eq!(Err("'-1' is not a number".to_string()), number::<u32>("-1"));
+        let error = ParseError {
+            line: 3,
+            message: number::<i32>("x").unwrap_err(),
+        };
+        assert_eq!("line 3: 'x' is not a number", error.to_string());
+    }
+}
@@ -1,11 +1,16 @@
-use bevy::{prelude::*, app::AppExit};
+use bevy::{prelude::*, app::AppExit, utils::HashSet};
 
+use crate::animation::Animation;
+use crate::bosses;
 use crate::collision;
 use crate::config;
 use crate::enemies;
+use crate::health::{self, Damage, DamageEvent, DeathEvent, Health, HitFlash};
 use crate::ui;
 use crate::camera;
 use crate::map;
+use crate::spritesheet::SheetSprites;
+use crate::weapons;
 
 pub struct PlayerPlugin;
 
@@ -21,9 +26,19 @@ impl Plugin for PlayerPlugin {
             .add_system(player_movement_system)
             .add_system(player_shooting_system)
             .add_system(despawn_shots_system)
-            .add_system(collide_with_enemies_system)
-            .add_system(collide_shots_with_enemies_system)
+            .add_system(collide_with_enemies_system
+                .after(collision::CollisionDetection)
+                .before(health::DamageApplication))
+            .add_system(collide_shots_with_enemies_system
+                .after(collision::CollisionDetection)
+                .before(health::DamageApplication))
+            .add_system(collide_with_enemy_shots_system
+                .after(collision::CollisionDetection)
+                .before(health::DamageApplication))
             .add_system(collide_with_walls_system)
+            .add_system(collide_with_hazards_system.before(health::DamageApplication))
+            .add_system(player_death_system.after(health::DamageApplication))
+            .add_system(collide_shots_with_tiles_system)
             .add_system(advancing_shots_system)
             .add_system(camera::camera_follow_player);
     }
@@ -32,7 +47,10 @@ impl Plugin for PlayerPlugin {
 #[derive(Component)]
 pub struct Player {
     movement_speed: f32,
-    pub health: i32,
+    // Time spent on hazardous tiles since the last damage
+    hazard_timer: Timer,
+    // Time touching a boss since the last damage
+    boss_timer: Timer,
 }
 
 #[derive(Component)]
@@ -41,35 +59,40 @@ struct Shot {
 }
 
 impl Player {
-    fn new(movement_speed: f32, health: i32) -> Player {
+    fn new(movement_speed: f32) -> Player {
         Player {
             movement_speed,
-            health,
+            hazard_timer: Timer::from_seconds(config::HAZARD_DAMAGE_INTERVAL, true),
+            boss_timer: Timer::from_seconds(config::BOSS_CRASH_INTERVAL, true),
         }
     }
 }
 
-fn setup_player(mut commands: Commands, asset_server: Res<AssetServer>) {
-    let ship_handle = asset_server.load("textures/ship_C.png");
-    commands
-        .spawn_bundle(SpriteBundle {
-            texture: ship_handle,
-            transform: Transform {
-                translation: Vec3::new(0.0, -330.0, 0.0),
-                scale: Vec3::new(1.0, 1.0, 0.0),
-                ..default()
-            },
-            ..default()
-        })
-        .insert(Player::new(config::PLAYER_SPEED, config::PLAYER_HEALTH));
+fn setup_player(mut commands: Commands, sprites: SheetSprites) {
+    let transform = Transform {
+        translation: Vec3::new(0.0, -330.0, 0.0),
+        scale: Vec3::new(1.0, 1.0, 0.0),
+        ..default()
+    };
+    sprites
+        .spawn(&mut commands, "ship_C", transform)
+        .insert(Player::new(config::PLAYER_SPEED))
+        .insert(Health::new(config::PLAYER_HEALTH))
+        .insert(HitFlash::default())
+        .insert(Animation::new("ship_C", "idle"))
+        .insert(collision::Collider {
+            layer: collision::LAYER_PLAYER,
+            mask: collision::LAYER_ENEMY,
+        });
 }
 
 fn player_movement_system(
     mut map: ResMut<map::Map>,
     keyboard_input: Res<Input<KeyCode>>,
-    mut query: Query<(&Player, &mut Transform)>,
+    mut query: Query<(&Player, &mut Transform, &mut Animation)>,
+    boss_query: Query<(), (With<bosses::Boss>, With<enemies::Advancing>)>,
 ) {
-    let (ship, mut transform) = query.single_mut();
+    let (ship, mut transform, mut animation) = query.single_mut();
 
     let mut horiz_movement_factor = 0.0;
     let mut vert_movement_factor = 0.0;
@@ -88,11 +111,27 @@ fn player_movement_system(
     }
     let movement_factor = Vec3::new(horiz_movement_factor, vert_movement_factor, 0.0);
 
+    // Banking into turns wins over the thrust
+    let clip = if horiz_movement_factor < 0.0 {
+        "bank_left"
+    } else if horiz_movement_factor > 0.0 {
+        "bank_right"
+    } else if vert_movement_factor > 0.0 {
+        "thrust"
+    } else {
+        "idle"
+    };
+    animation.play(clip);
+
     let movement_directions = transform.rotation * (Vec3::Y + Vec3::X);
     let movement_distance = movement_factor * ship.movement_speed * config::TIME_STEP;
     let mut translation_delta = movement_directions * movement_distance;
     //TODO(amatej): what values should be here? 10*translation_delta seems harly right
     map.scroll_speed = config::SCROLL_SPEED + translation_delta.y*10.0;
+    // The map waits for bosses to be destroyed
+    if !boss_query.is_empty() {
+        map.scroll_speed = 0.0;
+    }
     translation_delta.y = 0.0;
     transform.translation += translation_delta;
 
@@ -105,26 +144,26 @@ fn player_shooting_system(
     mut timer: ResMut<ShootingTimer>,
     time: Res<Time>,
     mut query: Query<&Transform, With<Player>>,
-    asset_server: Res<AssetServer>,
+    sprites: SheetSprites,
 ) {
     let transform = query.single_mut();
 
     if timer.0.tick(time.delta()).elapsed_secs() == config::SHOT_SPEED {
-        //TODO(amatej): I think the texture should be a resource? - load it just once
-        let shot_handle = asset_server.load("textures/shot.png");
-        commands
-            .spawn()
+        let shot_transform = Transform {
+            translation: transform.translation,
+            ..default()
+        };
+        sprites
+            .spawn(&mut commands, "shot", shot_transform)
             .insert(Shot {
                 movement_speed: config::SHOT_MOVEMENT_SEED,
             })
-            .insert_bundle(SpriteBundle {
-                texture: shot_handle,
-                transform: Transform {
-                    translation: transform.translation,
-                    ..default()
-                },
-                ..default()
-            });
+            .insert(Damage(config::SHOT_DAMAGE))
+            .insert(collision::Collider {
+                layer: collision::LAYER_SHOT,
+                mask: collision::LAYER_ENEMY,
+            })
+            .insert(collision::ContinuousCollision::new(&shot_transform));
         timer.0.reset();
     }
 }
@@ -132,33 +171,49 @@ fn player_shooting_system(
 fn advancing_shots_system(mut query: Query<(&Shot, &mut Transform)>) {
     let advancing_direction = Vec3::Y;
     for (shot, mut trans) in &mut query {
-        let advacing_distance = advancing_direction * shot.movement_speed * config::TIME_STEP;
-        let advacing_delta = advancing_direction * advacing_distance;
+        let advacing_delta = advancing_direction * shot.movement_speed * config::TIME_STEP;
         trans.translation += advacing_delta;
     }
 }
 
+// Enemies still in the fight and the hit zones of bosses, shots fly over the boss bodies
+type TargetQuery<'w, 's> = Query<
+    'w,
+    's,
+    Entity,
+    (
+        Or<(With<enemies::Advancing>, With<bosses::HitZone>)>,
+        Without<bosses::Boss>,
+    ),
+>;
+
 fn collide_shots_with_enemies_system(
     mut commands: Commands,
-    mut scoreboard: ResMut<ui::Scoreboard>,
-    imgs: Res<Assets<Image>>,
-    mut shots_query: Query<(Entity, &Transform, &Handle<Image>), With<Shot>>,
-    mut enemy_query: Query<(Entity, &Transform, &Handle<Image>), With<enemies::Advancing>>,
+    mut collision_events: EventReader<collision::CollisionEvent>,
+    mut damage_events: EventWriter<DamageEvent>,
+    shots_query: Query<&Damage, With<Shot>>,
+    enemy_query: TargetQuery,
 ) {
-    for (enemy, enemy_trans, enemy_img_handle) in &mut enemy_query {
-        if let Some(enemy_img) = imgs.get(enemy_img_handle) {
-            for (shot, shot_trans, shot_img_handle) in &mut shots_query {
-                if let Some(shot_img) = imgs.get(shot_img_handle) {
-                    let collision =
-                        collision::collide(enemy_trans, enemy_img, shot_trans, shot_img);
-                    if collision {
-                        scoreboard.score += 1;
-                        commands.entity(enemy).despawn();
-                        commands.entity(shot).despawn();
-                    }
-                }
-            }
+    // One shot can overlap more enemies (or the other way round) in the same frame,
+    // make sure each of them is used up just once
+    let mut hit: HashSet<Entity> = HashSet::new();
+    for event in collision_events.iter() {
+        let (shot, enemy) = event.ordered(|e| shots_query.get(e).is_ok());
+        if shots_query.get(shot).is_err() || enemy_query.get(enemy).is_err() {
+            continue;
+        }
+        if hit.contains(&shot) || hit.contains(&enemy) {
+            continue;
+        }
+        hit.insert(shot);
+        hit.insert(enemy);
+        if let Ok(damage) = shots_query.get(shot) {
+            damage_events.send(DamageEvent {
+                target: enemy,
+                amount: damage.0,
+            });
         }
+        commands.entity(shot).despawn();
     }
 }
 
@@ -178,49 +233,212 @@ fn despawn_shots_system(
     }
 }
 
+// Bodies and hit zones of bosses
+type BossPartQuery<'w, 's> =
+    Query<'w, 's, (), Or<(With<bosses::Boss>, With<bosses::HitZone>)>>;
+
+// Enemies crashing into the ship are destroyed, bosses stay and keep hurting it while it
+// touches them
 fn collide_with_enemies_system(
     mut commands: Commands,
-    imgs: Res<Assets<Image>>,
-    mut redraw_health: ResMut<ui::RedrawHealth>,
-    mut player_query: Query<(&Transform, &Handle<Image>, &mut Player)>,
+    time: Res<Time>,
+    mut collision_events: EventReader<collision::CollisionEvent>,
+    mut damage_events: EventWriter<DamageEvent>,
+    mut player_query: Query<(Entity, &mut Player)>,
+    enemy_query: Query<Entity, With<enemies::Enemy>>,
+    boss_query: BossPartQuery,
+) {
+    let (ship, mut player) = player_query.single_mut();
+    let mut hit: HashSet<Entity> = HashSet::new();
+    let mut touching_boss = false;
+    for event in collision_events.iter() {
+        let (other, enemy) = event.ordered(|e| e == ship);
+        if other != ship {
+            continue;
+        }
+        if boss_query.get(enemy).is_ok() {
+            touching_boss = true;
+            continue;
+        }
+        if enemy_query.get(enemy).is_err() || !hit.insert(enemy) {
+            continue;
+        }
+        commands.entity(enemy).despawn();
+        damage_events.send(DamageEvent {
+            target: ship,
+            amount: config::CRASH_DAMAGE,
+        });
+    }
+    if !touching_boss {
+        player.boss_timer.reset();
+        return;
+    }
+    // Right away on the first contact
+    let crash = player.boss_timer.elapsed().is_zero();
+    if player.boss_timer.tick(time.delta()).just_finished() || crash {
+        damage_events.send(DamageEvent {
+            target: ship,
+            amount: config::CRASH_DAMAGE,
+        });
+    }
+}
+
+fn collide_with_enemy_shots_system(
+    mut commands: Commands,
+    mut collision_events: EventReader<collision::CollisionEvent>,
+    mut damage_events: EventWriter<DamageEvent>,
+    player_query: Query<Entity, With<Player>>,
+    shots_query: Query<&Damage, With<weapons::EnemyShot>>,
+) {
+    let ship = player_query.single();
+    let mut hit: HashSet<Entity> = HashSet::new();
+    for event in collision_events.iter() {
+        let (other, shot) = event.ordered(|e| e == ship);
+        if other != ship || !hit.insert(shot) {
+            continue;
+        }
+        if let Ok(damage) = shots_query.get(shot) {
+            commands.entity(shot).despawn();
+            damage_events.send(DamageEvent {
+                target: ship,
+                amount: damage.0,
+            });
+        }
+    }
+}
+
+fn player_death_system(
+    mut death_events: EventReader<DeathEvent>,
+    player_query: Query<Entity, With<Player>>,
     mut app_exit_events: EventWriter<AppExit>,
-    mut enemy_query: Query<(Entity, &Transform, &Handle<Image>), With<enemies::Enemy>>,
 ) {
-    let (ship_transform, ship_img_handle, mut player) = player_query.single_mut();
-    if let Some(ship_img) = imgs.get(ship_img_handle) {
-        for (enemy, enemy_trans, enemy_img_handle) in &mut enemy_query {
-            if let Some(enemy_img) = imgs.get(enemy_img_handle) {
-                let collision =
-                    collision::collide(ship_transform, ship_img, enemy_trans, enemy_img);
-                if collision {
-                    commands.entity(enemy).despawn();
-                    if player.health > 1 {
-                        player.health -= 1;
-                        redraw_health.redraw = true;
-                    } else {
-                        app_exit_events.send(AppExit);
-                    }
+    let ship = player_query.single();
+    if death_events.iter().any(|death| death.entity == ship) {
+        app_exit_events.send(AppExit);
+    }
+}
+
+type TileQuery<'w, 's> = Query<
+    'w,
+    's,
+    (
+        &'static Transform,
+        &'static Handle<TextureAtlas>,
+        &'static TextureAtlasSprite,
+        &'static map::TileKind,
+    ),
+    With<map::Tile>,
+>;
+
+// Tiles the image at transform touches with their kind and translation
+fn touching_tiles(
+    map: &map::Map,
+    masks: &collision::CollisionMasks,
+    transform: &Transform,
+    mask: &collision::CollisionMask,
+    tile_query: &TileQuery,
+) -> Vec<(Entity, map::TileKind, Vec3)> {
+    let mut touching: Vec<(Entity, map::TileKind, Vec3)> = Vec::new();
+    // Only the tiles in the cells under the image can touch it
+    let (min, max) = collision::bounds(transform, mask.size());
+    for tile in map.grid.tiles_in_rect(min, max) {
+        if let Ok((tile_trans, tile_atlas, tile_sprite, kind)) = tile_query.get(tile) {
+            if let Some(tile_mask) = masks.get(tile_atlas, tile_sprite) {
+                if collision::collide(transform, mask, tile_trans, tile_mask) {
+                    touching.push((tile, *kind, tile_trans.translation));
                 }
             }
         }
     }
+    touching
 }
 
 fn collide_with_walls_system(
-    imgs: Res<Assets<Image>>,
-    mut player_query: Query<(&Transform, &Handle<Image>), With<Player>>,
+    map: Res<map::Map>,
+    masks: Res<collision::CollisionMasks>,
+    player_query: Query<(&Transform, &Handle<TextureAtlas>, &TextureAtlasSprite), With<Player>>,
     mut app_exit_events: EventWriter<AppExit>,
-    mut tile_query: Query<(&Transform, &Handle<Image>), With<map::Tile>>,
+    tile_query: TileQuery,
+) {
+    let (ship_trans, ship_atlas, ship_sprite) = player_query.single();
+    if let Some(ship_mask) = masks.get(ship_atlas, ship_sprite) {
+        let tiles = touching_tiles(&map, &masks, ship_trans, ship_mask, &tile_query);
+        if tiles.iter().any(|(_, kind, _)| {
+            matches!(
+                kind,
+                map::TileKind::Solid | map::TileKind::Destructible { .. }
+            )
+        }) {
+            app_exit_events.send(AppExit);
+        }
+    }
+}
+
+fn collide_with_hazards_system(
+    time: Res<Time>,
+    map: Res<map::Map>,
+    masks: Res<collision::CollisionMasks>,
+    mut damage_events: EventWriter<DamageEvent>,
+    mut player_query: Query<(
+        Entity,
+        &Transform,
+        &Handle<TextureAtlas>,
+        &TextureAtlasSprite,
+        &mut Player,
+    )>,
+    tile_query: TileQuery,
+) {
+    let (ship, ship_trans, ship_atlas, ship_sprite, mut player) = player_query.single_mut();
+    if let Some(ship_mask) = masks.get(ship_atlas, ship_sprite) {
+        let tiles = touching_tiles(&map, &masks, ship_trans, ship_mask, &tile_query);
+        if !tiles
+            .iter()
+            .any(|(_, kind, _)| *kind == map::TileKind::Hazardous)
+        {
+            player.hazard_timer.reset();
+            return;
+        }
+        if player.hazard_timer.tick(time.delta()).just_finished() {
+            damage_events.send(DamageEvent {
+                target: ship,
+                amount: config::HAZARD_DAMAGE,
+            });
+        }
+    }
+}
+
+// Shots break destructible tiles and stop on walls, they fly over the rest
+fn collide_shots_with_tiles_system(
+    mut commands: Commands,
+    mut map: ResMut<map::Map>,
+    masks: Res<collision::CollisionMasks>,
+    mut scoreboard: ResMut<ui::Scoreboard>,
+    shots_query: Query<
+        (Entity, &Transform, &Handle<TextureAtlas>, &TextureAtlasSprite),
+        With<Shot>,
+    >,
+    tile_query: TileQuery,
 ) {
-    let (ship_transform, ship_img_handle) = player_query.single_mut();
-    if let Some(ship_img) = imgs.get(ship_img_handle) {
-        for (tile_trans, tile_img_handle) in &mut tile_query {
-            if let Some(tile_img) = imgs.get(tile_img_handle) {
-                let collision =
-                    collision::collide(ship_transform, ship_img, tile_trans, tile_img);
-                if collision {
-                    app_exit_events.send(AppExit);
+    // More shots can hit the same tile in one frame
+    let mut broken: HashSet<Entity> = HashSet::new();
+    for (shot, shot_trans, shot_atlas, shot_sprite) in &shots_query {
+        if let Some(shot_mask) = masks.get(shot_atlas, shot_sprite) {
+            for (tile, kind, translation) in
+                touching_tiles(&map, &masks, shot_trans, shot_mask, &tile_query)
+            {
+                match kind {
+                    map::TileKind::Destructible { score } => {
+                        if broken.insert(tile) {
+                            scoreboard.score += score;
+                            map.grid.remove_tile(tile, translation);
+                            commands.entity(tile).despawn();
+                        }
+                    }
+                    map::TileKind::Solid => {}
+                    _ => continue,
                 }
+                commands.entity(shot).despawn();
+                break;
             }
         }
     }
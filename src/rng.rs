@@ -0,0 +1,81 @@
+use crate::config;
+use bevy::prelude::*;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+// All randomness in the game comes from here so a run can be replayed with the same seed.
+// Every part gets its own stream so e.g. spawning more enemies doesn't change the map.
+pub struct GameRng {
+    pub map: StdRng,
+    pub spawn: StdRng,
+    pub ai: StdRng,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> GameRng {
+        let mut streams = StdRng::seed_from_u64(seed);
+        GameRng {
+            map: StdRng::seed_from_u64(streams.gen()),
+            spawn: StdRng::seed_from_u64(streams.gen()),
+            ai: StdRng::seed_from_u64(streams.gen()),
+        }
+    }
+
+    // Seed from `--seed <number>` on the command line, random otherwise
+    pub fn from_args() -> GameRng {
+        let seed = match config::arg("--seed").map(|seed| seed.parse()) {
+            Some(Ok(seed)) => seed,
+            Some(Err(_)) => {
+                warn!("--seed needs a number, using a random seed");
+                rand::thread_rng().gen()
+            }
+            None => rand::thread_rng().gen(),
+        };
+        info!("Using seed: {}", seed);
+        GameRng::new(seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::generators::{self, RowGenerator};
+
+    // Draws of the spawn and ai streams, like picking a column and a speed for an enemy
+    type Spawn = (i32, f32);
+
+    // Rows and enemy spawns as the game would draw them
+    fn play(seed: u64) -> (Vec<Vec<i32>>, Vec<Spawn>) {
+        let mut rng = GameRng::new(seed);
+        let mut generator = generators::RandomRows::default();
+        let rows = (0..50)
+            .map(|index| generator.generate(index, &mut rng.map, &[]))
+            .collect();
+        let spawns = (0..50)
+            .map(|_| (rng.spawn.gen_range(-15..15), rng.ai.gen()))
+            .collect();
+        (rows, spawns)
+    }
+
+    #[test]
+    fn same_seed_same_game() {
+        assert_eq!(play(42), play(42));
+        assert_ne!(play(42), play(43));
+    }
+
+    #[test]
+    fn streams_are_independent() {
+        let mut a = GameRng::new(7);
+        let mut b = GameRng::new(7);
+        // Drawing from one stream doesn't shift the others
+        for _ in 0..10 {
+            b.spawn.gen::<u64>();
+        }
+        assert_eq!(a.ai.gen::<u64>(), b.ai.gen::<u64>());
+        let mut generator_a = generators::RandomRows::default();
+        let mut generator_b = generators::RandomRows::default();
+        assert_eq!(
+            generator_a.generate(0, &mut a.map, &[]),
+            generator_b.generate(0, &mut b.map, &[])
+        );
+    }
+}
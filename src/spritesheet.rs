@@ -0,0 +1,457 @@
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    ecs::system::{EntityCommands, SystemParam},
+    prelude::*,
+    reflect::TypeUuid,
+    render::texture::{CompressedImageFormats, ImageType},
+    sprite::Rect,
+    utils::HashMap,
+};
+use std::marker::PhantomData;
+
+use crate::parse::{number, ParseError};
+
+// Sheets are text files describing named regions of one image next to them:
+//   image <texture>
+//   cell <width> <height>
+//   <name> <column> <row> [<columns> <rows>]
+//   clip <name> <seconds per frame> loop|once <region>...
+// Regions are in cells of the last cell line before them and can span several cells. The
+// image becomes a texture atlas at "<sheet path>#atlas" with the regions in the order of
+// the file, sprites show them by their index and collision builds a mask for each of them.
+// Clips are animations made of regions defined above them. Lines starting with // are
+// comments.
+pub struct SpriteSheetPlugin;
+
+impl Plugin for SpriteSheetPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<SpriteSheet>()
+            .init_asset_loader::<SpriteSheetLoader>()
+            .init_resource::<Sprites>()
+            .add_system(show_regions_system);
+    }
+}
+
+// Sheet with the ships, enemies and shots
+pub const SPRITES: &str = "textures/sprites.sheet";
+
+// Keeps the sprites sheet loaded for the whole game
+pub struct Sprites(pub Handle<SpriteSheet>);
+
+impl FromWorld for Sprites {
+    fn from_world(world: &mut World) -> Sprites {
+        Sprites(world.resource::<AssetServer>().load(SPRITES))
+    }
+}
+
+#[derive(TypeUuid)]
+#[uuid = "0f6c2d5e-9b1a-4c47-8e3d-7a2b5c9f1e64"]
+pub struct SpriteSheet {
+    pub atlas: Handle<TextureAtlas>,
+    // Index of every region in the atlas
+    pub regions: HashMap<String, usize>,
+    pub clips: HashMap<String, Clip>,
+}
+
+impl SpriteSheet {
+    // Index of the region in the atlas, the first region if there is none of the name
+    pub fn index(&self, region: &str) -> usize {
+        self.regions.get(region).copied().unwrap_or_else(|| {
+            warn!("No region {} in {}", region, SPRITES);
+            0
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clip {
+    // Indices in the atlas
+    pub frames: Vec<usize>,
+    // Seconds each frame is shown
+    pub frame_time: f32,
+    // Starts over after the last frame, stays on it otherwise
+    pub looping: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SheetRegion {
+    pub name: String,
+    // Top left corner and size in pixels
+    pub min: UVec2,
+    pub size: UVec2,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SheetClip {
+    pub name: String,
+    pub frame_time: f32,
+    pub looping: bool,
+    // Region names
+    pub frames: Vec<String>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct SheetDescriptor {
+    // Path as written in the file
+    pub image: String,
+    pub regions: Vec<SheetRegion>,
+    pub clips: Vec<SheetClip>,
+}
+
+// Region of the sprites sheet a sprite spawned before the sheet was loaded has to show
+#[derive(Component)]
+pub struct PendingRegion(String);
+
+// Sprites showing regions of the sprites sheet
+#[derive(SystemParam)]
+pub struct SheetSprites<'w, 's> {
+    asset_server: Res<'w, AssetServer>,
+    sprites: Res<'w, Sprites>,
+    sheets: Res<'w, Assets<SpriteSheet>>,
+    #[system_param(ignore)]
+    marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> SheetSprites<'w, 's> {
+    // Sprite of the region at transform. Until the sheet is loaded it shows the first region
+    // and the region is pending, show_regions_system switches to it once the sheet is there.
+    pub fn spawn<'cw, 'cs, 'c>(
+        &self,
+        commands: &'c mut Commands<'cw, 'cs>,
+        region: &str,
+        transform: Transform,
+    ) -> EntityCommands<'cw, 'cs, 'c> {
+        let sheet = self.sheets.get(&self.sprites.0);
+        let texture_atlas = match sheet {
+            Some(sheet) => sheet.atlas.clone(),
+            None => {
+                let atlas = format!("{}#atlas", SPRITES);
+                self.asset_server.get_handle(atlas.as_str())
+            }
+        };
+        let mut entity = commands.spawn_bundle(SpriteSheetBundle {
+            sprite: TextureAtlasSprite::new(sheet.map_or(0, |sheet| sheet.index(region))),
+            texture_atlas,
+            transform,
+            ..default()
+        });
+        if sheet.is_none() {
+            entity.insert(PendingRegion(region.to_string()));
+        }
+        entity
+    }
+}
+
+fn show_regions_system(
+    mut commands: Commands,
+    sprites: Res<Sprites>,
+    sheets: Res<Assets<SpriteSheet>>,
+    mut query: Query<(Entity, &PendingRegion, &mut TextureAtlasSprite)>,
+) {
+    let sheet = match sheets.get(&sprites.0) {
+        Some(sheet) => sheet,
+        None => return,
+    };
+    for (entity, region, mut sprite) in &mut query {
+        sprite.index = sheet.index(&region.0);
+        commands.entity(entity).remove::<PendingRegion>();
+    }
+}
+
+pub fn parse_sheet(text: &str) -> Result<SheetDescriptor, ParseError> {
+    let mut image: Option<String> = None;
+    let mut cell: Option<UVec2> = None;
+    let mut regions: Vec<SheetRegion> = Vec::new();
+    let mut clips: Vec<SheetClip> = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let error = |message: String| ParseError {
+            line: line_index + 1,
+            message,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let numbers = |fields: &[&str]| -> Result<Vec<u32>, ParseError> {
+            fields
+                .iter()
+                .map(|field| number(field).map_err(error))
+                .collect()
+        };
+        match (fields[0], fields.len()) {
+            ("image", 2) if image.is_none() => image = Some(fields[1].to_string()),
+            ("image", _) => return Err(error("expected a single image <texture>".to_string())),
+            ("cell", 3) => {
+                let size = numbers(&fields[1..])?;
+                if size.contains(&0) {
+                    return Err(error("cells can't be empty".to_string()));
+                }
+                cell = Some(UVec2::new(size[0], size[1]));
+            }
+            ("cell", _) => return Err(error("expected cell <width> <height>".to_string())),
+            ("clip", len) if len >= 5 => {
+                if clips.iter().any(|clip| clip.name == fields[1]) {
+                    return Err(error(format!("clip '{}' is already defined", fields[1])));
+                }
+                let frame_time: f32 = match fields[2].parse() {
+                    Ok(time) if time > 0.0 => time,
+                    _ => return Err(error(format!("'{}' is not a frame time", fields[2]))),
+                };
+                let looping = match fields[3] {
+                    "loop" => true,
+                    "once" => false,
+                    _ => return Err(error(format!("expected loop or once, not '{}'", fields[3]))),
+                };
+                for frame in &fields[4..] {
+                    if !regions.iter().any(|region| region.name == *frame) {
+                        return Err(error(format!("unknown region '{}'", frame)));
+                    }
+                }
+                clips.push(SheetClip {
+                    name: fields[1].to_string(),
+                    frame_time,
+                    looping,
+                    frames: fields[4..].iter().map(|frame| frame.to_string()).collect(),
+                });
+            }
+            ("clip", _) => {
+                return Err(error(
+                    "expected clip <name> <seconds per frame> loop|once <region>...".to_string(),
+                ))
+            }
+            (name, 3 | 5) => {
+                let cell =
+                    cell.ok_or_else(|| error("regions need a cell size first".to_string()))?;
+                if regions.iter().any(|region| region.name == name) {
+                    return Err(error(format!("region '{}' is already defined", name)));
+                }
+                let numbers = numbers(&fields[1..])?;
+                let cells = match numbers.get(2..) {
+                    Some([columns, rows]) => UVec2::new(*columns, *rows),
+                    _ => UVec2::ONE,
+                };
+                regions.push(SheetRegion {
+                    name: name.to_string(),
+                    min: UVec2::new(numbers[0], numbers[1]) * cell,
+                    size: cells * cell,
+                });
+            }
+            _ => {
+                return Err(error(
+                    "expected <name> <column> <row> [<columns> <rows>]".to_string(),
+                ))
+            }
+        }
+    }
+    let image = image.ok_or(ParseError {
+        line: 1,
+        message: "sheet needs an image".to_string(),
+    })?;
+    Ok(SheetDescriptor {
+        image,
+        regions,
+        clips,
+    })
+}
+
+// Atlas of the texture with a rect for every region, in their order
+pub fn build_atlas(texture: Handle<Image>, size: Vec2, regions: &[SheetRegion]) -> TextureAtlas {
+    let mut atlas = TextureAtlas::new_empty(texture, size);
+    for region in regions {
+        atlas.add_texture(Rect {
+            min: region.min.as_vec2(),
+            max: (region.min + region.size).as_vec2(),
+        });
+    }
+    atlas
+}
+
+#[derive(Default)]
+pub struct SpriteSheetLoader;
+
+impl AssetLoader for SpriteSheetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let descriptor = parse_sheet(std::str::from_utf8(bytes)?)?;
+            let path = load_context
+                .path()
+                .parent()
+                .unwrap()
+                .join(&descriptor.image);
+            let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            let image = Image::from_buffer(
+                &load_context.read_asset_bytes(&path).await?,
+                ImageType::Extension(extension),
+                CompressedImageFormats::NONE,
+                true,
+            )?;
+            let image_size = image.size().as_uvec2();
+            for region in &descriptor.regions {
+                if (region.min + region.size).cmpgt(image_size).any() {
+                    return Err(bevy::asset::Error::msg(format!(
+                        "region '{}' doesn't fit into {}",
+                        region.name, descriptor.image
+                    )));
+                }
+            }
+            let texture = load_context.set_labeled_asset("image", LoadedAsset::new(image));
+            let atlas = build_atlas(texture, image_size.as_vec2(), &descriptor.regions);
+            let atlas = load_context.set_labeled_asset("atlas", LoadedAsset::new(atlas));
+            let regions: HashMap<String, usize> = descriptor
+                .regions
+                .into_iter()
+                .enumerate()
+                .map(|(index, region)| (region.name, index))
+                .collect();
+            let clips = descriptor
+                .clips
+                .into_iter()
+                .map(|clip| {
+                    let frames = clip.frames.iter().map(|frame| regions[frame]);
+                    let clip_data = Clip {
+                        frames: frames.collect(),
+                        frame_time: clip.frame_time,
+                        looping: clip.looping,
+                    };
+                    (clip.name, clip_data)
+                })
+                .collect();
+            load_context.set_default_asset(LoadedAsset::new(SpriteSheet {
+                atlas,
+                regions,
+                clips,
+            }));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["sheet"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_regions_in_cells() {
+        let descriptor = parse_sheet(
+            "// ships\n\
+             image ships.png\n\
+             cell 64 32\n\
+             ship 1 2\n\
+             \n\
+             boss 0 1 2 3\n\
+             cell 10 10\n\
+             shot 3 0\n\
+             clip fly 0.5 loop ship boss\n\
+             clip hit 0.1 once shot\n",
+        )
+        .unwrap();
+        let region = |name: &str, min: UVec2, size: UVec2| SheetRegion {
+            name: name.to_string(),
+            min,
+            size,
+        };
+        assert_eq!(
+            SheetDescriptor {
+                image: "ships.png".to_string(),
+                regions: vec![
+                    region("ship", UVec2::new(64, 64), UVec2::new(64, 32)),
+                    region("boss", UVec2::new(0, 32), UVec2::new(128, 96)),
+                    region("shot", UVec2::new(30, 0), UVec2::new(10, 10)),
+                ],
+                clips: vec![
+                    SheetClip {
+                        name: "fly".to_string(),
+                        frame_time: 0.5,
+                        looping: true,
+                        frames: vec!["ship".to_string(), "boss".to_string()],
+                    },
+                    SheetClip {
+                        name: "hit".to_string(),
+                        frame_time: 0.1,
+                        looping: false,
+                        frames: vec!["shot".to_string()],
+                    },
+                ],
+            },
+            descriptor
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_line() {
+        let error = parse_sheet("image a.png\ncell 8 8\nship 0 x\n")
+            .err()
+            .unwrap();
+        assert_eq!("line 3: 'x' is not a number", error.to_string());
+        assert_eq!(2, parse_sheet("image a.png\nship 0 0").err().unwrap().line);
+        assert_eq!(
+            4,
+            parse_sheet("image a.png\ncell 8 8\nship 0 0\nship 1 0")
+                .err()
+                .unwrap()
+                .line
+        );
+        assert_eq!(2, parse_sheet("image a.png\ncell 0 8").err().unwrap().line);
+        assert_eq!(
+            2,
+            parse_sheet("image a.png\nimage b.png").err().unwrap().line
+        );
+        assert!(parse_sheet("cell 8 8\nship 0 0").is_err());
+        let clip = |line: &str| parse_sheet(&format!("image a.png\ncell 8 8\nship 0 0\n{}", line));
+        assert!(clip("clip fly 0.1 loop ship").is_ok());
+        assert_eq!(
+            "line 4: unknown region 'boss'",
+            clip("clip fly 0.1 loop ship boss")
+                .err()
+                .unwrap()
+                .to_string()
+        );
+        assert_eq!(4, clip("clip fly 0 loop ship").err().unwrap().line);
+        assert_eq!(4, clip("clip fly 0.1 twice ship").err().unwrap().line);
+        assert_eq!(4, clip("clip fly 0.1 loop").err().unwrap().line);
+    }
+
+    #[test]
+    fn game_sheets_parse() {
+        let sprites = parse_sheet(include_str!("../assets/textures/sprites.sheet")).unwrap();
+        for name in ["ship_C", "enemy_A", "shot"] {
+            assert!(sprites.regions.iter().any(|region| region.name == name));
+        }
+        for name in ["ship_C_idle", "ship_C_thrust", "enemy_A_death"] {
+            assert!(sprites.clips.iter().any(|clip| clip.name == name));
+        }
+        assert!(parse_sheet(include_str!("../assets/textures/tiles/tiles.sheet")).is_ok());
+    }
+
+    #[test]
+    fn atlas_has_regions_in_order() {
+        let descriptor = parse_sheet("image a.png\ncell 8 4\nship 1 0\nboss 0 1 2 2\n").unwrap();
+        let atlas = build_atlas(
+            Handle::default(),
+            Vec2::new(16.0, 12.0),
+            &descriptor.regions,
+        );
+        assert_eq!(Vec2::new(16.0, 12.0), atlas.size);
+        let rects: Vec<(Vec2, Vec2)> = atlas
+            .textures
+            .iter()
+            .map(|rect| (rect.min, rect.max))
+            .collect();
+        assert_eq!(
+            vec![
+                (Vec2::new(8.0, 0.0), Vec2::new(16.0, 4.0)),
+                (Vec2::new(0.0, 4.0), Vec2::new(16.0, 12.0)),
+            ],
+            rects
+        );
+    }
+}
@@ -0,0 +1,287 @@
+use crate::map::TileKind;
+use crate::parse::{number, ParseError};
+use crate::spritesheet;
+use bevy::{
+    asset::{AssetLoader, AssetPath, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    render::texture::{CompressedImageFormats, ImageType},
+};
+use std::path::Path;
+
+// Tilesets are text files describing the tile textures next to them, one tile per line:
+//   <texture> <weight> <kind> [score] [autotile]
+// Texture is an image or a region of a sprite sheet like tiles.sheet#rock.
+// Weight is how often the tile is picked relative to the others, kind is one of solid,
+// destructible, decorative or hazardous and destructible tiles can give a score when
+// broken. Autotile textures are sheets with a variant for every combination of taken
+// neighbours, see AUTOTILE_NORTH. Every tile shows an index of a texture atlas, images become
+// atlases of their own and regions use the atlas of their sheet. Lines starting with // are
+// comments.
+pub struct TilesetPlugin;
+
+impl Plugin for TilesetPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Tileset>()
+            .init_asset_loader::<TilesetLoader>();
+    }
+}
+
+// Variants in an autotile sheet go left to right and top to bottom, the index is the sum of
+// the sides with a neighbouring tile
+pub const AUTOTILE_NORTH: usize = 1;
+pub const AUTOTILE_EAST: usize = 2;
+pub const AUTOTILE_SOUTH: usize = 4;
+pub const AUTOTILE_WEST: usize = 8;
+const AUTOTILE_COLUMNS: usize = 4;
+const AUTOTILE_ROWS: usize = 4;
+
+#[derive(TypeUuid)]
+#[uuid = "b3a0f3c8-2a7e-4f0e-8f7e-6c1d2a9e4b15"]
+pub struct Tileset {
+    pub tiles: Vec<TilesetTile>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TilesetTile {
+    pub atlas: Handle<TextureAtlas>,
+    // Index in the atlas of the tile without neighbours
+    pub index: usize,
+    pub weight: u32,
+    pub kind: TileKind,
+    // Atlas indices by neighbour mask, empty if the tile isn't autotiled
+    pub variants: Vec<usize>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct TilesetEntry {
+    // Path as written in the file
+    pub texture: String,
+    pub weight: u32,
+    pub kind: TileKind,
+    pub autotile: bool,
+}
+
+pub fn parse_tileset(text: &str) -> Result<Vec<TilesetEntry>, ParseError> {
+    let mut tiles: Vec<TilesetEntry> = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let error = |message: String| ParseError {
+            line: line_index + 1,
+            message,
+        };
+        let mut fields: Vec<&str> = line.split_whitespace().collect();
+        let autotile = fields.last() == Some(&"autotile");
+        if autotile {
+            fields.pop();
+        }
+        if fields.len() < 3 || fields.len() > 4 {
+            return Err(error(
+                "expected <texture> <weight> <kind> [score] [autotile]".to_string(),
+            ));
+        }
+        let weight: u32 = number(fields[1]).map_err(error)?;
+        let score: usize = match fields.get(3) {
+            Some(score) => number(score).map_err(error)?,
+            None => 1,
+        };
+        let kind = match fields[2] {
+            "solid" => TileKind::Solid,
+            "destructible" => TileKind::Destructible { score },
+            "decorative" => TileKind::Decorative,
+            "hazardous" => TileKind::Hazardous,
+            _ => return Err(error(format!("unknown kind '{}'", fields[2]))),
+        };
+        if fields.len() == 4 && !matches!(kind, TileKind::Destructible { .. }) {
+            return Err(error("only destructible tiles have a score".to_string()));
+        }
+        tiles.push(TilesetEntry {
+            texture: fields[0].to_string(),
+            weight,
+            kind,
+            autotile,
+        });
+    }
+    // Side walls are always solid
+    if !tiles
+        .iter()
+        .any(|tile| tile.weight > 0 && tile.kind == TileKind::Solid)
+    {
+        return Err(ParseError {
+            line: 1,
+            message: "tileset needs a solid tile to pick".to_string(),
+        });
+    }
+    Ok(tiles)
+}
+
+// Reads an image next to the tileset into an atlas of columns by rows equal cells, labeled
+// after its texture
+async fn load_grid_atlas<'a>(
+    load_context: &mut LoadContext<'a>,
+    texture: &str,
+    path: &Path,
+    columns: usize,
+    rows: usize,
+) -> Result<Handle<TextureAtlas>, bevy::asset::Error> {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    let image = Image::from_buffer(
+        &load_context.read_asset_bytes(path).await?,
+        ImageType::Extension(extension),
+        CompressedImageFormats::NONE,
+        true,
+    )?;
+    let cell = image.size() / Vec2::new(columns as f32, rows as f32);
+    let image =
+        load_context.set_labeled_asset(&format!("{}#image", texture), LoadedAsset::new(image));
+    let atlas = TextureAtlas::from_grid(image, cell, columns, rows);
+    Ok(load_context.set_labeled_asset(&format!("{}#atlas", texture), LoadedAsset::new(atlas)))
+}
+
+#[derive(Default)]
+pub struct TilesetLoader;
+
+impl AssetLoader for TilesetLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let folder = load_context.path().parent().unwrap().to_path_buf();
+            let mut sheets: Vec<AssetPath<'static>> = Vec::new();
+            let mut tiles: Vec<TilesetTile> = Vec::new();
+            for entry in parse_tileset(std::str::from_utf8(bytes)?)? {
+                let (atlas, index, variants) = match entry.texture.split_once('#') {
+                    // Regions are shown from the atlas of their sheet, by their place in it
+                    Some((sheet, region)) if !entry.autotile => {
+                        let path = folder.join(sheet);
+                        let descriptor = spritesheet::parse_sheet(std::str::from_utf8(
+                            &load_context.read_asset_bytes(&path).await?,
+                        )?)?;
+                        let index = descriptor
+                            .regions
+                            .iter()
+                            .position(|sheet_region| sheet_region.name == region)
+                            .ok_or_else(|| {
+                                bevy::asset::Error::msg(format!(
+                                    "no region '{}' in {}",
+                                    region, sheet
+                                ))
+                            })?;
+                        let path = AssetPath::from(path.as_path()).to_owned();
+                        let atlas =
+                            load_context.get_handle(AssetPath::new_ref(path.path(), Some("atlas")));
+                        sheets.push(path);
+                        (atlas, index, Vec::new())
+                    }
+                    Some(_) => {
+                        return Err(bevy::asset::Error::msg(format!(
+                            "autotile texture {} isn't an image",
+                            entry.texture
+                        )))
+                    }
+                    None if entry.autotile => {
+                        let path = folder.join(&entry.texture);
+                        let atlas = load_grid_atlas(
+                            load_context,
+                            &entry.texture,
+                            &path,
+                            AUTOTILE_COLUMNS,
+                            AUTOTILE_ROWS,
+                        )
+                        .await?;
+                        (atlas, 0, (0..AUTOTILE_COLUMNS * AUTOTILE_ROWS).collect())
+                    }
+                    None => {
+                        let path = folder.join(&entry.texture);
+                        let atlas =
+                            load_grid_atlas(load_context, &entry.texture, &path, 1, 1).await?;
+                        (atlas, 0, Vec::new())
+                    }
+                };
+                tiles.push(TilesetTile {
+                    atlas,
+                    index,
+                    weight: entry.weight,
+                    kind: entry.kind,
+                    variants,
+                });
+            }
+            load_context
+                .set_default_asset(LoadedAsset::new(Tileset { tiles }).with_dependencies(sheets));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["tileset"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(texture: &str, weight: u32, kind: TileKind, autotile: bool) -> TilesetEntry {
+        TilesetEntry {
+            texture: texture.to_string(),
+            weight,
+            kind,
+            autotile,
+        }
+    }
+
+    #[test]
+    fn parse_tiles() {
+        let tiles = parse_tileset(
+            "// texture weight kind\n\
+             t.png 10 solid autotile\n\
+             \n\
+             rock.png 3 destructible 5\n\
+             pebble.png 1 destructible\n\
+             deco.png 2 decorative\n\
+             hazard.png 0 hazardous\n",
+        )
+        .unwrap();
+        assert_eq!(
+            vec![
+                entry("t.png", 10, TileKind::Solid, true),
+                entry("rock.png", 3, TileKind::Destructible { score: 5 }, false),
+                entry("pebble.png", 1, TileKind::Destructible { score: 1 }, false),
+                entry("deco.png", 2, TileKind::Decorative, false),
+                entry("hazard.png", 0, TileKind::Hazardous, false),
+            ],
+            tiles
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_line() {
+        let error = parse_tileset("t.png 1 solid\nx.png 1 lava\n")
+            .err()
+            .unwrap();
+        assert_eq!(2, error.line);
+        assert_eq!("line 2: unknown kind 'lava'", error.to_string());
+        assert_eq!(1, parse_tileset("t.png one solid").err().unwrap().line);
+        assert_eq!(1, parse_tileset("t.png 1 solid 5").err().unwrap().line);
+        assert_eq!(
+            1,
+            parse_tileset("t.png 1 solid autotile 5")
+                .err()
+                .unwrap()
+                .line
+        );
+        assert!(parse_tileset("t.png 0 solid").is_err());
+        assert!(parse_tileset("rock.png 1 destructible").is_err());
+    }
+
+    #[test]
+    fn game_tileset_parses() {
+        let tiles = parse_tileset(include_str!("../assets/textures/tiles/tiles.tileset")).unwrap();
+        assert!(tiles.iter().any(|tile| tile.kind == TileKind::Solid));
+    }
+}
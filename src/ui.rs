@@ -1,28 +1,33 @@
 use bevy::prelude::*;
 
+use crate::bosses;
 use crate::config;
+use crate::health::Health;
 use crate::player;
 
 #[derive(Component)]
 pub struct Heart;
 
+// Bar at the top of the screen shown while a boss fights, the fill is its health
+#[derive(Component)]
+struct BossHealthBar;
+
+#[derive(Component)]
+struct BossHealthFill;
+
 pub struct UiPlugin;
 
 pub struct Scoreboard {
     pub score: usize,
 }
 
-pub struct RedrawHealth {
-    pub redraw: bool,
-}
-
 impl Plugin for UiPlugin {
     fn build(&self, app: &mut App) {
         app.insert_resource(Scoreboard { score: 0 });
-        app.insert_resource(RedrawHealth { redraw: false });
         app.add_startup_system(setup);
         app.add_system(update_scoreboard);
         app.add_system(update_health);
+        app.add_system(update_boss_health);
     }
 }
 
@@ -55,9 +60,40 @@ fn setup(mut commands: Commands, asset_server: Res<AssetServer>) {
         }),
     );
 
-    draw_health(&mut commands, config::PLAYER_HEALTH, &asset_server);
+    setup_boss_health(&mut commands);
+}
 
+fn setup_boss_health(commands: &mut Commands) {
+    commands.spawn_bundle(NodeBundle {
+        style: Style {
+            position_type: PositionType::Absolute,
+            position: UiRect {
+                top: config::BOSS_BAR_PADDING_TOP,
+                left: Val::Percent(20.0),
+                ..default()
+            },
+            size: Size::new(Val::Percent(60.0), config::BOSS_BAR_HEIGHT),
+            ..default()
+        },
+        color: config::BOSS_BAR_BACKGROUND_COLOR.into(),
+        visibility: Visibility { is_visible: false },
+        ..default()
+    })
+    .insert(BossHealthBar)
+    .with_children(|bar| {
+        bar.spawn_bundle(NodeBundle {
+            style: Style {
+                size: Size::new(Val::Percent(100.0), Val::Percent(100.0)),
+                ..default()
+            },
+            color: config::BOSS_BAR_COLOR.into(),
+            visibility: Visibility { is_visible: false },
+            ..default()
+        })
+        .insert(BossHealthFill);
+    });
 }
+
 fn draw_health(commands: &mut Commands, health: i32, asset_server: &Res<AssetServer>) {
     for i in 0..health {
         let left_padding: Val = config::SCOREBOARD_TEXT_PADDING + (i * 15) as f32;
@@ -72,30 +108,47 @@ fn draw_health(commands: &mut Commands, health: i32, asset_server: &Res<AssetSer
                 },
                 ..default()
             },
-            image: asset_server.load("textures/shot.png").into(),
+            image: asset_server.load("textures/ui/heart.png").into(),
             ..default()
         })
         .insert(Heart);
     }
 }
 
+// Hearts are drawn again whenever the health of the player changes, the first time too
 fn update_health(mut commands: Commands,
-                 redraw: Res<RedrawHealth>,
-                 mut players: Query<&player::Player>,
+                 players: Query<&Health, (With<player::Player>, Changed<Health>)>,
                  mut hearth_query: Query<Entity, With<Heart>>,
                  asset_server: Res<AssetServer>) {
-    if redraw.redraw {
+    for health in &players {
         for heart in &mut hearth_query {
             commands.entity(heart).despawn();
         }
 
-        for player in &mut players {
-            draw_health(&mut commands, player.health, &asset_server);
-        }
+        draw_health(&mut commands, health.current, &asset_server);
     }
 
 }
 
+type BossBarQuery<'w, 's> =
+    Query<'w, 's, &'static mut Visibility, Or<(With<BossHealthBar>, With<BossHealthFill>)>>;
+
+fn update_boss_health(bosses: Query<&bosses::Boss>,
+                      mut bar_query: BossBarQuery,
+                      mut fill_query: Query<&mut Style, With<BossHealthFill>>) {
+    let boss = bosses.iter().find(|boss| boss.health > 0);
+    // Children don't inherit the visibility of their parent
+    for mut visibility in &mut bar_query {
+        visibility.is_visible = boss.is_some();
+    }
+    if let Some(boss) = boss {
+        let percent = 100.0 * boss.health as f32 / boss.max_health as f32;
+        for mut style in &mut fill_query {
+            style.size.width = Val::Percent(percent);
+        }
+    }
+}
+
 fn update_scoreboard(scoreboard: Res<Scoreboard>, mut query: Query<&mut Text>) {
     let mut text = query.single_mut();
     text.sections[1].value = scoreboard.score.to_string();
@@ -0,0 +1,721 @@
+use bevy::{
+    asset::{AssetLoader, AssetPath, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+use rand::Rng;
+
+use crate::archetypes::{self, EnemyArchetype, EnemyArchetypes, Movement};
+use crate::bosses::{self, BossDescriptor};
+use crate::config;
+use crate::enemies;
+use crate::formations::{self, Formation};
+use crate::generators;
+use crate::map;
+use crate::parse::{number, ParseError};
+use crate::rng;
+use crate::spritesheet::SheetSprites;
+
+// Wave timelines are text files with one step per line, the director goes through them from
+// the top and starts the next step once the current one is done:
+//   wait <seconds>      pause
+//   scroll <tiles>      pause until the map scrolls the distance
+//   cleared             pause until no enemy is left
+//   spawn <kind> <count> <formation> <column> [movement] [via <column>,<row>...]
+//                       group of count enemies of the archetype kind, kind and column can be
+//                       random. Column 1 is right of the left wall like the first column of
+//                       levels and is the one of the leader in formations, movement
+//                       overrides the one of the archetype. Enemies moving along paths
+//                       fly through the via waypoints first, rows count down from the top
+//                       where they come in.
+//   boss <path>         boss of the file in assets, cleared waits until it is destroyed
+//   loop <difficulty>   last line, starts over with health and speed of the enemies scaled
+//                       by the difficulty once more
+// Formations are
+//   none                every enemy on its own, they come one after another
+//   v, line, column     enemies around the leader in the shape
+//   circle              enemies on a circle around the leader
+// Lines starting with // are comments.
+pub struct WavesPlugin;
+
+impl Plugin for WavesPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<Timeline>()
+            .init_asset_loader::<TimelineLoader>()
+            .add_startup_system(load_timeline)
+            .add_system(wave_director_system);
+    }
+}
+
+// Timeline of random maps
+pub const WAVES: &str = "waves/endless.waves";
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct SpawnGroup {
+    // None for random ones
+    pub kind: Option<char>,
+    pub count: u32,
+    pub formation: Formation,
+    pub column: Option<i32>,
+    pub movement: Option<Movement>,
+    // Waypoints the enemies fly through before finding their own way, on the map as it is
+    // when they spawn
+    pub path: Vec<map::Pos>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Step {
+    Wait(f32),
+    Scroll(f32),
+    Cleared,
+    Spawn(SpawnGroup),
+    // Path of the boss file in assets
+    Boss(String),
+}
+
+#[derive(Debug, PartialEq, TypeUuid)]
+#[uuid = "c71e5a90-2d84-4b3f-a6c8-8f0e9d3b5a12"]
+pub struct Timeline {
+    pub steps: Vec<Step>,
+    // Difficulty multiplier of every loop, the timeline ends without it
+    pub looping: Option<f32>,
+}
+
+impl Timeline {
+    // Step numbers (from one) and kinds of the spawn steps no archetype has
+    pub fn unknown_kinds(&self, archetypes: &EnemyArchetypes) -> Vec<(usize, char)> {
+        self.steps
+            .iter()
+            .enumerate()
+            .filter_map(|(index, step)| match step {
+                Step::Spawn(SpawnGroup {
+                    kind: Some(kind), ..
+                }) if archetypes.get(*kind).is_none() => Some((index + 1, *kind)),
+                _ => None,
+            })
+            .collect()
+    }
+}
+
+// Goes through the timeline of the game
+pub struct WaveDirector {
+    timeline: Handle<Timeline>,
+    step: usize,
+    // Seconds and pixels since the current step started or since the last spawn of a group
+    elapsed: f32,
+    scrolled: f32,
+    // Enemies of the current group spawned so far
+    spawned: u32,
+    // Health and speed multiplier of the enemies
+    difficulty: f32,
+    // Kinds of the timeline were checked against the archetypes
+    checked: bool,
+}
+
+impl WaveDirector {
+    fn new(timeline: Handle<Timeline>) -> WaveDirector {
+        WaveDirector {
+            timeline,
+            step: 0,
+            elapsed: 0.0,
+            scrolled: 0.0,
+            spawned: 0,
+            difficulty: 1.0,
+            checked: false,
+        }
+    }
+
+    // Moves delta seconds and distance pixels forward, returns the spawn or boss step to spawn
+    // the next enemy of. Cleared tells there is no enemy left.
+    fn update<'a>(
+        &mut self,
+        timeline: &'a Timeline,
+        delta: f32,
+        distance: f32,
+        cleared: bool,
+    ) -> Option<&'a Step> {
+        self.elapsed += delta;
+        self.scrolled += distance;
+        let step = match timeline.steps.get(self.step) {
+            Some(step) => step,
+            None => {
+                if let Some(difficulty) = timeline.looping {
+                    self.difficulty *= difficulty;
+                    self.step = 0;
+                    self.start_step();
+                }
+                return None;
+            }
+        };
+        match step {
+            Step::Wait(seconds) if self.elapsed >= *seconds => self.next_step(),
+            Step::Scroll(tiles) if self.scrolled >= tiles * config::TILE_SIDE => self.next_step(),
+            Step::Cleared if cleared => self.next_step(),
+            Step::Spawn(..) if self.spawned == 0 || self.elapsed >= config::WAVE_SPAWN_INTERVAL => {
+                return Some(step)
+            }
+            Step::Boss(..) => return Some(step),
+            _ => {}
+        }
+        None
+    }
+
+    // Counts enemies of the group update returned as spawned
+    fn spawned(&mut self, group: &SpawnGroup, count: u32) {
+        self.spawned += count;
+        self.elapsed = 0.0;
+        if self.spawned >= group.count {
+            self.next_step();
+        }
+    }
+
+    fn next_step(&mut self) {
+        self.step += 1;
+        self.start_step();
+    }
+
+    fn start_step(&mut self) {
+        self.elapsed = 0.0;
+        self.scrolled = 0.0;
+        self.spawned = 0;
+    }
+}
+
+// World x, archetype and movement speed offset of the next enemy of the group, None if the
+// archetype doesn't exist. The archetype is scaled by the difficulty.
+pub fn pick(
+    group: &SpawnGroup,
+    difficulty: f32,
+    rng: &mut rng::GameRng,
+    archetypes: &EnemyArchetypes,
+) -> Option<(f32, EnemyArchetype, f32)> {
+    let archetype = match group.kind {
+        Some(kind) => archetypes.get(kind)?,
+        None => archetypes.random(&mut rng.spawn),
+    };
+    let cell = match group.column {
+        Some(column) => generators::FIRST_CELL + column,
+        None => {
+            let half_range = (config::TILES_PER_WIDTH - archetype.size) / 2;
+            rng.spawn.gen_range(-half_range..half_range)
+        }
+    };
+    let mut archetype = archetype.scaled(difficulty);
+    if let Some(movement) = group.movement {
+        archetype.movement = movement;
+    }
+    let speed = archetype.random_speed(&mut rng.ai);
+    Some((cell as f32 * config::TILE_SIDE, archetype, speed))
+}
+
+// Timeline picked by `--waves <path in assets>` on the command line, WAVES otherwise
+fn load_timeline(mut commands: Commands, asset_server: Res<AssetServer>) {
+    let path = config::arg("--waves").unwrap_or_else(|| WAVES.to_string());
+    commands.insert_resource(WaveDirector::new(asset_server.load(&path)));
+}
+
+type EnemyQuery<'w, 's> = Query<'w, 's, (), Or<(With<enemies::Enemy>, With<bosses::Boss>)>>;
+
+#[allow(clippy::too_many_arguments)]
+fn wave_director_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    map: Res<map::Map>,
+    mut director: ResMut<WaveDirector>,
+    mut rng: ResMut<rng::GameRng>,
+    asset_server: Res<AssetServer>,
+    sprites: SheetSprites,
+    timelines: Res<Assets<Timeline>>,
+    archetypes: Res<archetypes::Archetypes>,
+    archetype_assets: Res<Assets<EnemyArchetypes>>,
+    boss_assets: Res<Assets<BossDescriptor>>,
+    enemy_query: EnemyQuery,
+) {
+    // Levels place their enemies themselves
+    if map.level.is_some() {
+        return;
+    }
+    let (timeline, archetypes) = match (
+        timelines.get(&director.timeline),
+        archetype_assets.get(&archetypes.0),
+    ) {
+        (Some(timeline), Some(archetypes)) => (timeline, archetypes),
+        _ => return,
+    };
+    if !director.checked {
+        for (step, kind) in timeline.unknown_kinds(archetypes) {
+            error!(
+                "Step {} of the waves spawns kind {}, no archetype in {} has it",
+                step,
+                kind,
+                archetypes::ARCHETYPES
+            );
+        }
+        director.checked = true;
+    }
+    let distance = map.scroll_speed * config::TIME_STEP;
+    let cleared = enemy_query.is_empty();
+    let group = match director.update(timeline, time.delta_seconds(), distance, cleared) {
+        Some(Step::Spawn(group)) => group,
+        Some(Step::Boss(path)) => {
+            // Loaded with the timeline already, waits for it otherwise
+            let boss: Handle<BossDescriptor> = asset_server.load(path.as_str());
+            if let Some(boss) = boss_assets.get(&boss) {
+                bosses::spawn_boss(&mut commands, &sprites, boss, director.difficulty);
+                director.next_step();
+            }
+            return;
+        }
+        _ => return,
+    };
+    let difficulty = director.difficulty;
+    let (x, archetype, speed) = match pick(group, difficulty, &mut rng, archetypes) {
+        Some(spawn) => spawn,
+        None => {
+            warn!("No enemy archetype for kind {:?} in the waves", group.kind);
+            director.next_step();
+            return;
+        }
+    };
+    // Waits for a free spot, random columns get another try. Formations only need it for
+    // the leader, the rest of it finds its place later.
+    if !enemies::spawn_clear(&map, x, archetype.size) {
+        return;
+    }
+    let translation = Vec3::new(x, config::MAP_BOUNDS.y / 2.0, 0.0);
+    match group.formation {
+        Formation::None => {
+            enemies::spawn_enemy(
+                &mut commands,
+                &sprites,
+                &archetype,
+                translation,
+                speed,
+                group.path.clone(),
+            );
+            director.spawned(group, 1);
+        }
+        formation => {
+            formations::spawn_formation(
+                &mut commands,
+                &sprites,
+                formation,
+                group.count,
+                &archetype,
+                translation,
+                speed,
+                group.path.clone(),
+            );
+            director.spawned(group, group.count);
+        }
+    }
+}
+
+pub fn parse_timeline(text: &str) -> Result<Timeline, ParseError> {
+    let mut steps: Vec<Step> = Vec::new();
+    let mut looping: Option<f32> = None;
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let error = |message: String| ParseError {
+            line: line_index + 1,
+            message,
+        };
+        if looping.is_some() {
+            return Err(error("loop has to be the last step".to_string()));
+        }
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        // Waypoints of spawns don't count as arguments
+        let (fields, waypoints) = match fields.iter().position(|&field| field == "via") {
+            Some(via) if fields[0] == "spawn" => fields.split_at(via),
+            _ => (&fields[..], &[][..]),
+        };
+        let expected = match fields[0] {
+            "wait" | "scroll" | "loop" => 2..=2,
+            "cleared" => 1..=1,
+            "spawn" => 5..=6,
+            "boss" => 2..=2,
+            _ => return Err(error(format!("unknown step '{}'", fields[0]))),
+        };
+        if !expected.contains(&fields.len()) {
+            return Err(error(format!(
+                "wrong number of arguments for {}: {}",
+                fields[0],
+                fields.len() - 1
+            )));
+        }
+        let step = match fields[0] {
+            "wait" => Step::Wait(number(fields[1]).map_err(error)?),
+            "scroll" => Step::Scroll(number(fields[1]).map_err(error)?),
+            "cleared" => Step::Cleared,
+            "boss" if !fields[1].ends_with(".boss") => {
+                return Err(error(format!("'{}' is not a boss file", fields[1])))
+            }
+            "boss" => Step::Boss(fields[1].to_string()),
+            "loop" => {
+                let difficulty: f32 = number(fields[1]).map_err(error)?;
+                if difficulty <= 0.0 {
+                    return Err(error("difficulty has to be over zero".to_string()));
+                }
+                looping = Some(difficulty);
+                continue;
+            }
+            _ => Step::Spawn(parse_spawn(&fields[1..], waypoints).map_err(error)?),
+        };
+        if let Step::Wait(amount) | Step::Scroll(amount) = step {
+            if amount < 0.0 {
+                return Err(error(format!("{} can't go back", fields[0])));
+            }
+        }
+        steps.push(step);
+    }
+    let error = |message: &str| {
+        Err(ParseError {
+            line: 1,
+            message: message.to_string(),
+        })
+    };
+    if !steps
+        .iter()
+        .any(|step| matches!(step, Step::Spawn(..) | Step::Boss(..)))
+    {
+        return error("timeline spawns no enemies");
+    }
+    // Spawns alone would go round within a few frames
+    if looping.is_some()
+        && !steps
+            .iter()
+            .any(|step| matches!(step, Step::Wait(..) | Step::Scroll(..) | Step::Cleared))
+    {
+        return error("looping timeline needs a wait, scroll or cleared step");
+    }
+    Ok(Timeline { steps, looping })
+}
+
+// Arguments of a spawn step
+// Fields of a spawn step after its name, waypoints start with via when there are any
+fn parse_spawn(fields: &[&str], waypoints: &[&str]) -> Result<SpawnGroup, String> {
+    let kind = match fields[0].chars().collect::<Vec<char>>()[..] {
+        _ if fields[0] == "random" => None,
+        [kind @ 'A'..='Z'] => Some(kind),
+        _ => return Err(format!("kind '{}' is not a letter A-Z", fields[0])),
+    };
+    let count: u32 = number(fields[1])?;
+    if count == 0 {
+        return Err("a group needs at least one enemy".to_string());
+    }
+    let formation = match fields[2] {
+        "none" => Formation::None,
+        "v" => Formation::V,
+        "line" => Formation::Line,
+        "column" => Formation::Column,
+        "circle" => Formation::Circle,
+        _ => return Err(format!("unknown formation '{}'", fields[2])),
+    };
+    let column = match fields[3] {
+        "random" => None,
+        column => match column.parse() {
+            Ok(column) if (1..config::TILES_PER_WIDTH).contains(&column) => Some(column),
+            _ => {
+                return Err(format!(
+                    "column '{}' is not between the walls, 1 to {}",
+                    column,
+                    config::TILES_PER_WIDTH - 1
+                ))
+            }
+        },
+    };
+    let movement = match fields.get(4) {
+        None => None,
+        Some(name) => match Movement::parse(name) {
+            Some(movement) => Some(movement),
+            None => return Err(format!("unknown movement '{}'", name)),
+        },
+    };
+    if waypoints.len() == 1 {
+        return Err("via needs at least one waypoint".to_string());
+    }
+    let path = waypoints
+        .iter()
+        .skip(1)
+        .map(|waypoint| parse_waypoint(waypoint))
+        .collect::<Result<Vec<map::Pos>, String>>()?;
+    Ok(SpawnGroup {
+        kind,
+        count,
+        formation,
+        column,
+        movement,
+        path,
+    })
+}
+
+// Position on the map of a <column>,<row> waypoint
+fn parse_waypoint(waypoint: &str) -> Result<map::Pos, String> {
+    let (column, row) = waypoint
+        .split_once(',')
+        .ok_or_else(|| format!("waypoint '{}' is not <column>,<row>", waypoint))?;
+    let (column, row): (i32, i32) = (number(column)?, number(row)?);
+    if !(1..config::TILES_PER_WIDTH).contains(&column)
+        || !(0..=config::ROWS_PER_HEIGHT).contains(&row)
+    {
+        return Err(format!(
+            "waypoint '{}' is not on the map, columns 1 to {} and rows 0 to {}",
+            waypoint,
+            config::TILES_PER_WIDTH - 1,
+            config::ROWS_PER_HEIGHT
+        ));
+    }
+    Ok(map::Pos {
+        x: column,
+        y: config::ROWS_PER_HEIGHT - row,
+    })
+}
+
+#[derive(Default)]
+pub struct TimelineLoader;
+
+impl AssetLoader for TimelineLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let timeline = parse_timeline(std::str::from_utf8(bytes)?)?;
+            // Bosses are ready by the time they come
+            let bosses: Vec<AssetPath<'static>> = timeline
+                .steps
+                .iter()
+                .filter_map(|step| match step {
+                    Step::Boss(path) => Some(AssetPath::from(path.as_str()).to_owned()),
+                    _ => None,
+                })
+                .collect();
+            load_context.set_default_asset(LoadedAsset::new(timeline).with_dependencies(bosses));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["waves"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Group of a spawn step update returned
+    fn group_of(step: Option<&Step>) -> &SpawnGroup {
+        match step {
+            Some(Step::Spawn(group)) => group,
+            _ => panic!("{:?} is not a spawn", step),
+        }
+    }
+
+    fn spawn_group(kind: Option<char>, count: u32) -> SpawnGroup {
+        SpawnGroup {
+            kind,
+            count,
+            formation: Formation::None,
+            column: None,
+            movement: None,
+            path: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn parse_steps() {
+        let timeline = parse_timeline(
+            "// opening\n\
+             spawn random 3 none random\n\
+             \n\
+             wait 1.5\n\
+             spawn B 2 none 4 straight\n\
+             spawn A 5 v 16\n\
+             spawn C 1 none 3 via 3,4 10,32\n\
+             scroll 10\n\
+             boss bosses/big.boss\n\
+             cleared\n\
+             loop 1.5\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Timeline {
+                steps: vec![
+                    Step::Spawn(spawn_group(None, 3)),
+                    Step::Wait(1.5),
+                    Step::Spawn(SpawnGroup {
+                        column: Some(4),
+                        movement: Some(Movement::Straight),
+                        ..spawn_group(Some('B'), 2)
+                    }),
+                    Step::Spawn(SpawnGroup {
+                        formation: Formation::V,
+                        column: Some(16),
+                        ..spawn_group(Some('A'), 5)
+                    }),
+                    Step::Spawn(SpawnGroup {
+                        column: Some(3),
+                        path: vec![
+                            map::Pos {
+                                x: 3,
+                                y: config::ROWS_PER_HEIGHT - 4
+                            },
+                            map::Pos { x: 10, y: 0 },
+                        ],
+                        ..spawn_group(Some('C'), 1)
+                    }),
+                    Step::Scroll(10.0),
+                    Step::Boss("bosses/big.boss".to_string()),
+                    Step::Cleared,
+                ],
+                looping: Some(1.5),
+            },
+            timeline
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_line() {
+        let valid = "spawn A 1 none random\nwait 1\n";
+        let line_of = |line: &str| {
+            parse_timeline(&format!("{}{}", valid, line))
+                .err()
+                .unwrap()
+                .line
+        };
+        assert_eq!(3, line_of("jump 1"));
+        assert_eq!(3, line_of("boss bosses/big.png"));
+        assert_eq!(3, line_of("wait"));
+        assert_eq!(3, line_of("wait -1"));
+        assert_eq!(3, line_of("spawn a 1 none random"));
+        assert_eq!(3, line_of("spawn A 0 none random"));
+        assert_eq!(3, line_of("spawn A 1 blob random"));
+        assert_eq!(3, line_of("spawn A 1 none 0"));
+        assert_eq!(3, line_of("spawn A 1 none 5 zigzag"));
+        assert_eq!(3, line_of("spawn A 1 none 5 via"));
+        assert_eq!(3, line_of("spawn A 1 none 5 straight via 5"));
+        assert_eq!(3, line_of("spawn A 1 none 5 via 0,5"));
+        assert_eq!(3, line_of("spawn A 1 none 5 via 5,33"));
+        assert_eq!(3, line_of("wait 1 via 5,5"));
+        assert_eq!(4, line_of("loop 1\nwait 1"));
+        assert_eq!(
+            "line 1: 'x' is not a number",
+            parse_timeline("scroll x").err().unwrap().to_string()
+        );
+        // Whole timeline checks
+        assert!(parse_timeline("wait 1").is_err());
+        assert!(parse_timeline("spawn A 1 none random\nloop 1").is_err());
+    }
+
+    #[test]
+    fn director_waits_for_each_step() {
+        let timeline = parse_timeline(
+            "wait 1\nscroll 2\ncleared\nspawn A 2 none random\nspawn B 1 none random\n\
+             boss bosses/big.boss",
+        )
+        .unwrap();
+        let mut director = WaveDirector::new(Handle::default());
+        assert_eq!(None, director.update(&timeline, 0.5, 0.0, true));
+        // Waiting is done, the step after it starts on the next update
+        assert_eq!(None, director.update(&timeline, 0.5, 0.0, true));
+        assert_eq!(
+            None,
+            director.update(&timeline, 1.0, config::TILE_SIDE, true)
+        );
+        assert_eq!(
+            None,
+            director.update(&timeline, 1.0, config::TILE_SIDE, true)
+        );
+        assert_eq!(None, director.update(&timeline, 0.0, 0.0, false));
+        assert_eq!(None, director.update(&timeline, 0.0, 0.0, true));
+        let first = group_of(director.update(&timeline, 0.0, 0.0, true));
+        assert_eq!(Some('A'), first.kind);
+        director.spawned(first, 1);
+        // The rest of the group comes a bit later
+        assert_eq!(None, director.update(&timeline, 0.1, 0.0, true));
+        let second = group_of(director.update(&timeline, config::WAVE_SPAWN_INTERVAL, 0.0, true));
+        assert_eq!(Some('A'), second.kind);
+        director.spawned(second, 1);
+        let last = group_of(director.update(&timeline, 0.0, 0.0, true));
+        assert_eq!(Some('B'), last.kind);
+        director.spawned(last, 1);
+        // Bosses stay until they spawn
+        let boss = Step::Boss("bosses/big.boss".to_string());
+        assert_eq!(Some(&boss), director.update(&timeline, 1.0, 0.0, true));
+        assert_eq!(Some(&boss), director.update(&timeline, 1.0, 0.0, true));
+        director.next_step();
+        // Timeline without loop ends
+        for _ in 0..3 {
+            assert_eq!(None, director.update(&timeline, 10.0, 0.0, true));
+        }
+    }
+
+    #[test]
+    fn director_loops_with_difficulty() {
+        let timeline = parse_timeline("spawn A 1 none random\nwait 1\nloop 1.5").unwrap();
+        let mut director = WaveDirector::new(Handle::default());
+        for expected_difficulty in [1.0, 1.5, 2.25] {
+            let group = group_of(director.update(&timeline, 0.0, 0.0, true));
+            assert_eq!(expected_difficulty, director.difficulty);
+            director.spawned(group, 1);
+            director.update(&timeline, 1.0, 0.0, true);
+            assert_eq!(None, director.update(&timeline, 0.0, 0.0, true));
+        }
+    }
+
+    #[test]
+    fn pick_scales_and_overrides_archetype() {
+        let archetypes =
+            archetypes::parse_archetypes("A a 1 2 10-10 1 3 path none\nB b 0 1 0-1 1 1 path none")
+                .unwrap();
+        let mut rng = rng::GameRng::new(0);
+        let group = SpawnGroup {
+            column: Some(1),
+            movement: Some(Movement::Straight),
+            ..spawn_group(Some('A'), 1)
+        };
+        let (x, archetype, speed) = pick(&group, 2.0, &mut rng, &archetypes).unwrap();
+        assert_eq!((generators::FIRST_CELL + 1) as f32 * config::TILE_SIDE, x);
+        assert_eq!(4, archetype.health);
+        assert_eq!(20.0, speed);
+        assert_eq!(Movement::Straight, archetype.movement);
+        // Random groups only pick archetypes with weight
+        for _ in 0..20 {
+            let (_, archetype, _) =
+                pick(&spawn_group(None, 1), 1.0, &mut rng, &archetypes).unwrap();
+            assert_eq!('A', archetype.kind);
+        }
+        assert!(pick(&spawn_group(Some('C'), 1), 1.0, &mut rng, &archetypes).is_none());
+    }
+
+    #[test]
+    fn game_timeline_parses() {
+        let timeline = parse_timeline(include_str!("../assets/waves/endless.waves")).unwrap();
+        let archetypes =
+            archetypes::parse_archetypes(include_str!("../assets/enemies/archetypes.enemies"))
+                .unwrap();
+        // Every kind of the timeline has an archetype
+        assert_eq!(
+            Vec::<(usize, char)>::new(),
+            timeline.unknown_kinds(&archetypes)
+        );
+    }
+
+    #[test]
+    fn unknown_kinds_name_their_step() {
+        let timeline = parse_timeline(
+            "spawn A 1 none random
+wait 1
+spawn C 2 v 3
+spawn random 1 none 1",
+        )
+        .unwrap();
+        let archetypes = archetypes::parse_archetypes("A a 1 2 10-10 1 3 path none").unwrap();
+        assert_eq!(vec![(3, 'C')], timeline.unknown_kinds(&archetypes));
+    }
+}
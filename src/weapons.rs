@@ -0,0 +1,410 @@
+use bevy::{
+    asset::{AssetLoader, BoxedFuture, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+};
+
+use crate::collision;
+use crate::config;
+use crate::enemies;
+use crate::health::Damage;
+use crate::parse::{number, ParseError};
+use crate::player;
+use crate::spritesheet::SheetSprites;
+
+// Enemy weapons are text files with one weapon per line:
+//   <name> <sprite> <seconds> <speed> <damage> <pattern> [bullets] [degrees]
+// Archetypes name the weapon of their enemies. Seconds is the time between volleys, sprite
+// the region of the bullets in the sprites sheet. Patterns are
+//   aimed                       one bullet at the player
+//   fan <bullets> <degrees>     bullets spread over the degrees around the player
+//   radial <bullets>            bullets all around
+//   spiral <bullets> <degrees>  bullets all around, turned by the degrees every volley
+// Lines starting with // are comments.
+pub struct WeaponsPlugin;
+
+impl Plugin for WeaponsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<EnemyWeapons>()
+            .init_asset_loader::<WeaponsLoader>()
+            .add_startup_system(load_weapons)
+            .add_system(enemy_shooting_system)
+            .add_system(advancing_enemy_shots_system)
+            .add_system(despawn_enemy_shots_system);
+    }
+}
+
+// Weapons archetypes pick from
+pub const WEAPONS: &str = "enemies/weapons.weapons";
+
+pub struct Weapons(pub Handle<EnemyWeapons>);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Pattern {
+    Aimed,
+    Fan { bullets: u32, spread: f32 },
+    Radial { bullets: u32 },
+    Spiral { bullets: u32, turn: f32 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnemyWeapon {
+    pub name: String,
+    pub sprite: String,
+    pub interval: f32,
+    pub speed: f32,
+    pub damage: i32,
+    pub pattern: Pattern,
+}
+
+#[derive(TypeUuid)]
+#[uuid = "5d2f8b3e-7a41-4c96-9e0d-2b6a1f4c8e77"]
+pub struct EnemyWeapons {
+    pub weapons: Vec<EnemyWeapon>,
+}
+
+impl EnemyWeapons {
+    pub fn get(&self, name: &str) -> Option<&EnemyWeapon> {
+        self.weapons.iter().find(|weapon| weapon.name == name)
+    }
+}
+
+// Weapon of an enemy, the rest of it is looked up by name in the weapons
+#[derive(Component)]
+pub struct Weapon {
+    name: String,
+    // Seconds since the last volley
+    elapsed: f32,
+    volleys: u32,
+}
+
+impl Weapon {
+    pub fn new(name: &str) -> Weapon {
+        Weapon {
+            name: name.to_string(),
+            elapsed: 0.0,
+            volleys: 0,
+        }
+    }
+}
+
+// Bullets of enemies, they only hit the player
+#[derive(Component)]
+pub struct EnemyShot {
+    velocity: Vec2,
+}
+
+// Unit directions of the bullets in a volley, aim points at the player. Bullets all around
+// start straight down.
+pub fn directions(pattern: &Pattern, aim: Vec2, volley: u32) -> Vec<Vec2> {
+    let around = |bullets: u32, turn: f32| -> Vec<Vec2> {
+        (0..bullets)
+            .map(|index| {
+                let angle = 360.0 * index as f32 / bullets as f32 + turn;
+                Mat2::from_angle(angle.to_radians()) * Vec2::NEG_Y
+            })
+            .collect()
+    };
+    match *pattern {
+        Pattern::Aimed => vec![aim],
+        Pattern::Fan { bullets: 1, .. } => vec![aim],
+        Pattern::Fan { bullets, spread } => (0..bullets)
+            .map(|index| {
+                let angle = spread * (index as f32 / (bullets - 1) as f32 - 0.5);
+                Mat2::from_angle(angle.to_radians()) * aim
+            })
+            .collect(),
+        Pattern::Radial { bullets } => around(bullets, 0.0),
+        Pattern::Spiral { bullets, turn } => around(bullets, turn * volley as f32),
+    }
+}
+
+fn load_weapons(mut commands: Commands, asset_server: Res<AssetServer>) {
+    commands.insert_resource(Weapons(asset_server.load(WEAPONS)));
+}
+
+fn enemy_shooting_system(
+    mut commands: Commands,
+    time: Res<Time>,
+    sprites: SheetSprites,
+    weapons: Res<Weapons>,
+    weapon_assets: Res<Assets<EnemyWeapons>>,
+    player_query: Query<&Transform, With<player::Player>>,
+    mut enemy_query: Query<(Entity, &Transform, &mut Weapon), With<enemies::Advancing>>,
+) {
+    let weapons = match weapon_assets.get(&weapons.0) {
+        Some(weapons) => weapons,
+        None => return,
+    };
+    let target = player_query.get_single().ok();
+    for (enemy, transform, mut weapon) in &mut enemy_query {
+        let enemy_weapon = match weapons.get(&weapon.name) {
+            Some(enemy_weapon) => enemy_weapon,
+            None => {
+                warn!("Enemy weapon {} is not defined", weapon.name);
+                commands.entity(enemy).remove::<Weapon>();
+                continue;
+            }
+        };
+        weapon.elapsed += time.delta_seconds();
+        if weapon.elapsed < enemy_weapon.interval {
+            continue;
+        }
+        weapon.elapsed -= enemy_weapon.interval;
+        let aim = target
+            .map(|target| (target.translation - transform.translation).truncate())
+            .and_then(|aim| aim.try_normalize())
+            .unwrap_or(Vec2::NEG_Y);
+        for direction in directions(&enemy_weapon.pattern, aim, weapon.volleys) {
+            let shot_transform = Transform::from_translation(transform.translation);
+            sprites
+                .spawn(&mut commands, &enemy_weapon.sprite, shot_transform)
+                .insert(EnemyShot {
+                    velocity: direction * enemy_weapon.speed,
+                })
+                .insert(Damage(enemy_weapon.damage))
+                .insert(collision::Collider {
+                    layer: collision::LAYER_ENEMY_SHOT,
+                    mask: collision::LAYER_PLAYER,
+                });
+        }
+        weapon.volleys += 1;
+    }
+}
+
+fn advancing_enemy_shots_system(mut query: Query<(&EnemyShot, &mut Transform)>) {
+    for (shot, mut transform) in &mut query {
+        transform.translation += (shot.velocity * config::TIME_STEP).extend(0.0);
+    }
+}
+
+// Despawns enemy shots that leave the screen on any side
+fn despawn_enemy_shots_system(
+    mut commands: Commands,
+    query: Query<(Entity, &Transform), With<EnemyShot>>,
+) {
+    for (shot, transform) in &query {
+        if transform
+            .translation
+            .truncate()
+            .abs()
+            .cmpgt(config::MAP_BOUNDS / 2.0)
+            .any()
+        {
+            commands.entity(shot).despawn();
+        }
+    }
+}
+
+pub fn parse_weapons(text: &str) -> Result<EnemyWeapons, ParseError> {
+    let mut weapons: Vec<EnemyWeapon> = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("//") {
+            continue;
+        }
+        let error = |message: String| ParseError {
+            line: line_index + 1,
+            message,
+        };
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 6 {
+            return Err(error(
+                "expected <name> <sprite> <seconds> <speed> <damage> <pattern>".to_string(),
+            ));
+        }
+        if weapons.iter().any(|weapon| weapon.name == fields[0]) {
+            return Err(error(format!("weapon {} is already defined", fields[0])));
+        }
+        let interval: f32 = number(fields[2]).map_err(error)?;
+        if interval <= 0.0 {
+            return Err(error(
+                "seconds between volleys have to be over zero".to_string(),
+            ));
+        }
+        let arguments = &fields[6..];
+        let bullets = || -> Result<u32, ParseError> {
+            let bullets: u32 = number(arguments[0]).map_err(error)?;
+            if bullets == 0 {
+                return Err(error("a volley needs at least one bullet".to_string()));
+            }
+            Ok(bullets)
+        };
+        let pattern = match (fields[5], arguments.len()) {
+            ("aimed", 0) => Pattern::Aimed,
+            ("fan", 2) => Pattern::Fan {
+                bullets: bullets()?,
+                spread: number(arguments[1]).map_err(error)?,
+            },
+            ("radial", 1) => Pattern::Radial {
+                bullets: bullets()?,
+            },
+            ("spiral", 2) => Pattern::Spiral {
+                bullets: bullets()?,
+                turn: number(arguments[1]).map_err(error)?,
+            },
+            ("aimed" | "fan" | "radial" | "spiral", count) => {
+                return Err(error(format!(
+                    "wrong number of arguments for {}: {}",
+                    fields[5], count
+                )))
+            }
+            _ => return Err(error(format!("unknown pattern '{}'", fields[5]))),
+        };
+        weapons.push(EnemyWeapon {
+            name: fields[0].to_string(),
+            sprite: fields[1].to_string(),
+            interval,
+            speed: number(fields[3]).map_err(error)?,
+            damage: number(fields[4]).map_err(error)?,
+            pattern,
+        });
+    }
+    Ok(EnemyWeapons { weapons })
+}
+
+#[derive(Default)]
+pub struct WeaponsLoader;
+
+impl AssetLoader for WeaponsLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), bevy::asset::Error>> {
+        Box::pin(async move {
+            let weapons = parse_weapons(std::str::from_utf8(bytes)?)?;
+            load_context.set_default_asset(LoadedAsset::new(weapons));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["weapons"]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::archetypes;
+
+    fn assert_close(expected: Vec2, actual: Vec2) {
+        assert!(
+            expected.abs_diff_eq(actual, 0.0001),
+            "{} is not {}",
+            actual,
+            expected
+        );
+    }
+
+    #[test]
+    fn parse_weapon_patterns() {
+        let weapons = parse_weapons(
+            "// name sprite seconds speed damage pattern\n\
+             cannon shot 1.5 300 1 aimed\n\
+             \n\
+             shotgun shot 2 250 2 fan 5 60\n\
+             burst shot 2.5 200 1 radial 12\n\
+             spinner shot 0.3 200 1 spiral 3 15\n",
+        )
+        .unwrap();
+        assert_eq!(
+            EnemyWeapon {
+                name: "shotgun".to_string(),
+                sprite: "shot".to_string(),
+                interval: 2.0,
+                speed: 250.0,
+                damage: 2,
+                pattern: Pattern::Fan {
+                    bullets: 5,
+                    spread: 60.0
+                },
+            },
+            weapons.weapons[1]
+        );
+        assert_eq!(Pattern::Aimed, weapons.get("cannon").unwrap().pattern);
+        assert_eq!(
+            Pattern::Radial { bullets: 12 },
+            weapons.get("burst").unwrap().pattern
+        );
+        assert_eq!(
+            Pattern::Spiral {
+                bullets: 3,
+                turn: 15.0
+            },
+            weapons.get("spinner").unwrap().pattern
+        );
+    }
+
+    #[test]
+    fn parse_errors_have_line() {
+        let valid = "cannon shot 1 300 1 aimed\n";
+        let line_of = |line: &str| {
+            parse_weapons(&format!("{}{}", valid, line))
+                .err()
+                .unwrap()
+                .line
+        };
+        assert_eq!(2, line_of("cannon shot 1 300 1 aimed"));
+        assert_eq!(2, line_of("gun shot 0 300 1 aimed"));
+        assert_eq!(2, line_of("gun shot 1 300 1 aimed 3"));
+        assert_eq!(2, line_of("gun shot 1 300 1 fan 3"));
+        assert_eq!(2, line_of("gun shot 1 300 1 radial 0"));
+        assert_eq!(2, line_of("gun shot 1 300 1 laser"));
+        assert_eq!(
+            "line 1: 'x' is not a number",
+            parse_weapons("gun shot 1 300 1 spiral x 15")
+                .err()
+                .unwrap()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn fan_spreads_around_aim() {
+        let pattern = Pattern::Fan {
+            bullets: 3,
+            spread: 90.0,
+        };
+        let directions = directions(&pattern, Vec2::NEG_Y, 0);
+        assert_eq!(3, directions.len());
+        assert_close(Vec2::new(-1.0, -1.0).normalize(), directions[0]);
+        assert_close(Vec2::NEG_Y, directions[1]);
+        assert_close(Vec2::new(1.0, -1.0).normalize(), directions[2]);
+        let single = Pattern::Fan {
+            bullets: 1,
+            spread: 90.0,
+        };
+        assert_eq!(vec![Vec2::X], super::directions(&single, Vec2::X, 0));
+    }
+
+    #[test]
+    fn spiral_turns_every_volley() {
+        let radial = directions(&Pattern::Radial { bullets: 4 }, Vec2::X, 7);
+        assert_close(Vec2::NEG_Y, radial[0]);
+        assert_close(Vec2::X, radial[1]);
+        assert_close(Vec2::Y, radial[2]);
+        let spiral = Pattern::Spiral {
+            bullets: 2,
+            turn: 45.0,
+        };
+        assert_close(Vec2::NEG_Y, directions(&spiral, Vec2::X, 0)[0]);
+        assert_close(Vec2::X, directions(&spiral, Vec2::X, 2)[0]);
+        assert_close(Vec2::NEG_X, directions(&spiral, Vec2::X, 2)[1]);
+    }
+
+    #[test]
+    fn game_weapons_parse() {
+        let weapons = parse_weapons(include_str!("../assets/enemies/weapons.weapons")).unwrap();
+        let archetypes =
+            archetypes::parse_archetypes(include_str!("../assets/enemies/archetypes.enemies"))
+                .unwrap();
+        // Every weapon of the game archetypes is defined
+        for archetype in archetypes.archetypes {
+            if let Some(weapon) = archetype.weapon {
+                assert!(weapons.get(&weapon).is_some(), "{} is not defined", weapon);
+            }
+        }
+    }
+}